@@ -1,6 +1,6 @@
 use cosmwasm_schema::write_api;
 
-use angel_staking::msg::{ExecuteMsg, QueryMsg, InstantiateMsg};
+use angel_staking::msg::{ExecuteMsg, QueryMsg, InstantiateMsg, MigrateMsg};
 
 
 fn main() {
@@ -8,5 +8,6 @@ fn main() {
        instantiate: InstantiateMsg,
        execute: ExecuteMsg,
        query: QueryMsg,
+       migrate: MigrateMsg,
     }
 }
\ No newline at end of file
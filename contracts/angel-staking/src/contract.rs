@@ -1,49 +1,202 @@
 
+use std::collections::BTreeMap;
+
 // #[cfg(not(feature = "library"))]
 use cosmwasm_std::entry_point;
 use cosmwasm_std::{
-    coin, to_binary, Addr, BankMsg, Binary, Deps, DepsMut, Env,
-    MessageInfo, QuerierWrapper, Response, StakingMsg, StdResult, Uint128, Uint64,
-    Order, Coin, DistributionMsg, CosmosMsg,
+    coin, to_binary, Addr, BankMsg, Binary, Decimal, Deps, DepsMut, Env,
+    MessageInfo, QuerierWrapper, Response, BlockInfo, StdError, Timestamp, StakingMsg, StdResult, Storage, Uint128, Uint256, Uint64,
+    Order, Coin, DistributionMsg, CosmosMsg, Event, Reply, SubMsg, Validator, WasmMsg, from_binary,
 };
 
-use cw2::set_contract_version;
+use cw2::{get_contract_version, set_contract_version};
+use cw_controllers::{Claim, ClaimsResponse};
+use cw721::{Cw721ExecuteMsg, Cw721QueryMsg, Cw721ReceiveMsg, OwnerOfResponse};
+use cw_storage_plus::{Bound, Item};
+use semver::Version;
+use serde::{de::DeserializeOwned, Serialize};
 use cw_utils::{one_coin, PaymentError, Duration, Expiration};
 
 use crate::error::ContractError;
-use crate::msg::{ExecuteMsg, InstantiateMsg,  QueryMsg};
-use crate::state::{BONDED, CLAIMED, TOTAL_BONDED, TOTAL_CLAIMED, AGENT, MANAGER, CLAIMS, State, NUMBER_VALIDATORS, ValidatorInfo, TREASURY };
+use crate::msg::{ExecuteMsg, InstantiateMsg, MigrateMsg, QueryMsg, PositionResponse, AllPositionsResponse, ValidatorHealth,
+    ValidatorHealthResponse, ValidatorStatus, UnbondBatchResponse,
+    ClaimStatusResponse, ReceiveNftMsg, PendingProposalsResponse, RoleProposalResponse,
+    AgentResponse, ListAgentsResponse, ReconciliationResponse, ValidatorReconciliation};
+use crate::state::{BONDED, CLAIMED, TOTAL_BONDED, TOTAL_CLAIMED, MANAGER, State, NUMBER_VALIDATORS, ValidatorInfo, TREASURY, POSITIONS, NftPosition,
+    REWARD_INDEX, PENDING_REWARDS, FEE_BPS, TOTAL_SHARES, REWARD_MODE, RewardMode, UNBOND_STRATEGY, UnbondStrategy, REWARD_WITHDRAWAL, RewardWithdrawal,
+    VALIDATOR_REWARDS, TOTAL_REWARDS, MAX_COMMISSION, UNBONDING_ENTRIES, UNBOND_EPOCH, PENDING_BATCH,
+    UNBOND_BATCHES, UnbondBatch, UNBOND_CLAIMS, NFT_CONTRACT, SELF_SERVICE, ESCROWED_NFTS,
+    TokenId, LEGACY_CLAIMS, LEGACY_VALIDATORS, LegacyValidatorInfo, ROLE_PROPOSALS, Role, RoleProposal,
+    AGENTS, AgentPermissions, AgentAction, LEGACY_AGENT, GUARDIAN, PAUSED };
 
 // version info for migration info
 const CONTRACT_NAME: &str = "crates.io:cw-staking-angel";
 const CONTRACT_VERSION: &str = env!("CARGO_PKG_VERSION");
 
+// fee_bps is expressed in basis points of the collected rewards
+const MAX_FEE_BPS: u64 = 10_000;
+
+// weight of a validator added without one
+const DEFAULT_VALIDATOR_WEIGHT: u64 = 1;
+
+// the staking module rejects undelegations to a validator with this many entries still unbonding
+const MAX_UNBONDING_ENTRIES: usize = 7;
+
+// seconds an unbond batch collects requests by default. With 21 days of unbonding, 7 batches are in flight at most
+const DEFAULT_UNBOND_EPOCH: u64 = 3 * 24 * 60 * 60;
+
+// reply ids
+const REPLY_WITHDRAW_REWARDS: u64 = 1;
+
+// pagination info for queries
+const DEFAULT_LIMIT: u32 = 10;
+const MAX_LIMIT: u32 = 30;
+
 
 #[cfg_attr(not(feature = "library"), entry_point)]
 pub fn instantiate(
     deps: DepsMut,
-    _env: Env,
+    env: Env,
     _info: MessageInfo,
     msg: InstantiateMsg,
 ) -> Result<Response, ContractError> {
     set_contract_version(deps.storage, CONTRACT_NAME, CONTRACT_VERSION)?;
 
     deps.api.addr_validate(&msg.manager)?;
-    deps.api.addr_validate(&msg.agent)?;
+    let agent = deps.api.addr_validate(&msg.agent)?;
     deps.api.addr_validate(&msg.treasury)?;
+    let nft_contract = deps.api.addr_validate(&msg.nft_contract)?;
     
-    AGENT.save(deps.storage, &msg.agent)?;
+    AGENTS.save(deps.storage, &agent, &AgentPermissions::all())?;
     MANAGER.save(deps.storage, &msg.manager)?;
     TREASURY.save(deps.storage, &msg.treasury)?;
+    NFT_CONTRACT.save(deps.storage, &nft_contract)?;
+    SELF_SERVICE.save(deps.storage, &false)?;
+    PAUSED.save(deps.storage, &false)?;
     BONDED.save(deps.storage, &Uint128::zero())?;
     CLAIMED.save(deps.storage, &Uint128::zero())?;
     TOTAL_BONDED.save(deps.storage, &Uint128::zero())?;
     TOTAL_CLAIMED.save(deps.storage, &Uint128::zero())?;
     NUMBER_VALIDATORS.save(deps.storage, &Uint64::zero())?;
+    REWARD_INDEX.save(deps.storage, &Decimal::zero())?;
+    PENDING_REWARDS.save(deps.storage, &Uint128::zero())?;
+    FEE_BPS.save(deps.storage, &0u64)?;
+    TOTAL_SHARES.save(deps.storage, &Uint128::zero())?;
+    REWARD_MODE.save(deps.storage, &RewardMode::Payout)?;
+    UNBOND_STRATEGY.save(deps.storage, &UnbondStrategy::Proportional)?;
+    TOTAL_REWARDS.save(deps.storage, &Uint128::zero())?;
+    MAX_COMMISSION.save(deps.storage, &Decimal::one())?;
+    UNBOND_EPOCH.save(deps.storage, &DEFAULT_UNBOND_EPOCH)?;
+    PENDING_BATCH.save(deps.storage, &0u64)?;
+    UNBOND_BATCHES.save(deps.storage, 0u64, &UnbondBatch::new(env.block.time))?;
 
     Ok(Response::default())   
 }
 
+#[cfg_attr(not(feature = "library"), entry_point)]
+pub fn migrate(mut deps: DepsMut, env: Env, msg: MigrateMsg) -> Result<Response, ContractError> {
+    let stored = get_contract_version(deps.storage)?;
+    if stored.contract != CONTRACT_NAME {
+        return Err(ContractError::WrongContract { contract: stored.contract });
+    }
+    if parse_version(&stored.version)? > parse_version(CONTRACT_VERSION)? {
+        return Err(ContractError::CannotDowngrade { from: stored.version, to: CONTRACT_VERSION.to_string() });
+    }
+
+    // Every step only rewrites the state still in an older layout, so they run on every migration
+    let initialized = migrate_config(deps.branch(), msg)?;
+    let validators = migrate_validators(deps.storage)?;
+    let agents = migrate_agents(deps.storage)?;
+    let legacy_claims = migrate_legacy_claims(deps.storage, env.block.time)?;
+    migrate_unbond_batches(deps.storage, env.block.time)?;
+
+    set_contract_version(deps.storage, CONTRACT_NAME, CONTRACT_VERSION)?;
+
+    Ok(Response::new()
+        .add_attribute("action", "migrate")
+        .add_attribute("from_version", stored.version)
+        .add_attribute("to_version", CONTRACT_VERSION)
+        .add_attribute("initialized", initialized.to_string())
+        .add_attribute("validators", validators.to_string())
+        .add_attribute("legacy_claims", legacy_claims.to_string())
+        .add_attribute("agents", agents.to_string()))
+}
+
+fn parse_version(version: &str) -> Result<Version, ContractError> {
+    Version::parse(version).map_err(|_| ContractError::InvalidVersion { version: version.to_string() })
+}
+
+// Saves the config items added after the deployed version with their instantiate value. Returns how many were missing
+fn migrate_config(deps: DepsMut, msg: MigrateMsg) -> Result<usize, ContractError> {
+    let mut initialized = 0;
+    if NFT_CONTRACT.may_load(deps.storage)?.is_none() {
+        let nft_contract = msg.nft_contract.ok_or(ContractError::MissingNftContract {})?;
+        NFT_CONTRACT.save(deps.storage, &deps.api.addr_validate(&nft_contract)?)?;
+        initialized += 1;
+    }
+    initialized += init_item(deps.storage, &SELF_SERVICE, false)?;
+    initialized += init_item(deps.storage, &PAUSED, false)?;
+    initialized += init_item(deps.storage, &REWARD_INDEX, Decimal::zero())?;
+    initialized += init_item(deps.storage, &PENDING_REWARDS, Uint128::zero())?;
+    initialized += init_item(deps.storage, &FEE_BPS, 0u64)?;
+    initialized += init_item(deps.storage, &TOTAL_SHARES, Uint128::zero())?;
+    initialized += init_item(deps.storage, &REWARD_MODE, RewardMode::Payout)?;
+    initialized += init_item(deps.storage, &UNBOND_STRATEGY, UnbondStrategy::Proportional)?;
+    initialized += init_item(deps.storage, &TOTAL_REWARDS, Uint128::zero())?;
+    initialized += init_item(deps.storage, &MAX_COMMISSION, Decimal::one())?;
+    initialized += init_item(deps.storage, &UNBOND_EPOCH, DEFAULT_UNBOND_EPOCH)?;
+    Ok(initialized)
+}
+
+fn init_item<T: Serialize + DeserializeOwned>(storage: &mut dyn Storage, item: &Item<T>, value: T) -> StdResult<usize> {
+    if item.may_load(storage)?.is_some() {
+        return Ok(0);
+    }
+    item.save(storage, &value)?;
+    Ok(1)
+}
+
+// Rewrites every validator in the current ValidatorInfo layout. Validators without weight get the default one
+fn migrate_validators(storage: &mut dyn Storage) -> StdResult<usize> {
+    let legacy : Vec<(String, LegacyValidatorInfo)> = LEGACY_VALIDATORS
+        .range(storage, None, None, Order::Ascending)
+        .collect::<StdResult<_>>()?;
+    let state = State::default();
+    for (address, info) in &legacy {
+        let validator_info = ValidatorInfo {
+            bond_denom: info.bond_denom.clone(),
+            unbonding_period: info.unbonding_period,
+            bonded: info.bonded,
+            claimed: info.claimed,
+            weight: info.weight.unwrap_or(DEFAULT_VALIDATOR_WEIGHT),
+            max_bonded: info.max_bonded,
+        };
+        // The old value can not be loaded, the indexed fields did not change
+        state.validator.replace(storage, address, Some(&validator_info), Some(&validator_info))?;
+    }
+    NUMBER_VALIDATORS.save(storage, &Uint64::new(legacy.len() as u64))?;
+    Ok(legacy.len())
+}
+
+// Moves the single agent of the deployed version to the agent set, allowed to do everything it did before
+fn migrate_agents(storage: &mut dyn Storage) -> StdResult<usize> {
+    let Some(agent) = LEGACY_AGENT.may_load(storage)? else {
+        return Ok(0);
+    };
+    AGENTS.save(storage, &Addr::unchecked(agent), &AgentPermissions::all())?;
+    LEGACY_AGENT.remove(storage);
+    Ok(1)
+}
+
+// Opens the first batch when the deployed version did not batch unbonds
+fn migrate_unbond_batches(storage: &mut dyn Storage, now: Timestamp) -> StdResult<()> {
+    if PENDING_BATCH.may_load(storage)?.is_none() {
+        PENDING_BATCH.save(storage, &0u64)?;
+        UNBOND_BATCHES.save(storage, 0u64, &UnbondBatch::new(now))?;
+    }
+    Ok(())
+}
+
 #[cfg_attr(not(feature = "library"), entry_point)]
 pub fn execute(
     deps: DepsMut,
@@ -53,138 +206,244 @@ pub fn execute(
 ) -> Result<Response, ContractError> {
     match msg {
         ExecuteMsg::Bond {nft_id} => execute_bond(deps, env, info, nft_id),
+        ExecuteMsg::ReceiveNft(msg) => execute_receive_nft(deps, env, info, msg),
         ExecuteMsg::Unbond { nft_id, amount } => execute_unbond(deps, env, info, nft_id, amount),
-        ExecuteMsg::Claim {nft_id, sender} => execute_claim(deps, env, info, nft_id, sender),
-        ExecuteMsg::AddValidator { address, bond_denom, unbonding_period } => execute_add_validator (deps, env, info, address, bond_denom, unbonding_period),
+        ExecuteMsg::ProcessUnbondBatch {} => execute_process_unbond_batch(deps, env, info),
+        ExecuteMsg::SetUnbondEpoch { epoch } => execute_set_unbond_epoch(deps, env, info, epoch),
+        ExecuteMsg::SetSelfService { enabled } => execute_set_self_service(deps, env, info, enabled),
+        ExecuteMsg::ProposeManager { address, expires } => execute_propose_role(deps, env, info, Role::Manager, address, None, expires),
+        ExecuteMsg::AcceptManager {} => execute_accept_role(deps, env, info, Role::Manager),
+        ExecuteMsg::ProposeAgent { address, permissions, expires } => execute_propose_role(deps, env, info, Role::Agent, address, Some(permissions), expires),
+        ExecuteMsg::AcceptAgent {} => execute_accept_role(deps, env, info, Role::Agent),
+        ExecuteMsg::ProposeTreasury { address, expires } => execute_propose_role(deps, env, info, Role::Treasury, address, None, expires),
+        ExecuteMsg::AcceptTreasury {} => execute_accept_role(deps, env, info, Role::Treasury),
+        ExecuteMsg::SetAgentPermissions { address, permissions } => execute_set_agent_permissions(deps, env, info, address, permissions),
+        ExecuteMsg::RemoveAgent { address } => execute_remove_agent(deps, env, info, address),
+        ExecuteMsg::SetGuardian { address } => execute_set_guardian(deps, env, info, address),
+        ExecuteMsg::Pause {} => execute_pause(deps, env, info),
+        ExecuteMsg::Unpause {} => execute_unpause(deps, env, info),
+        ExecuteMsg::Claim {nft_id} => execute_claim(deps, env, info, nft_id),
+        ExecuteMsg::AddValidator { address, bond_denom, unbonding_period, weight, max_bonded } => execute_add_validator (deps, env, info, address, bond_denom, unbonding_period, weight, max_bonded),
+        ExecuteMsg::SetValidatorWeight { address, weight } => execute_set_validator_weight(deps, env, info, address, weight),
+        ExecuteMsg::SetValidatorMaxBonded { address, max_bonded } => execute_set_validator_max_bonded(deps, env, info, address, max_bonded),
+        ExecuteMsg::SetMaxCommission { max_commission } => execute_set_max_commission(deps, env, info, max_commission),
         ExecuteMsg::RemoveValidator { address } => execute_remove_validator (deps, env, info, address, ),
         ExecuteMsg::BondCheck {} => execute_bond_check(deps.as_ref(), env, info),
+        ExecuteMsg::SyncSlashing {} => execute_sync_slashing(deps, env, info),
         ExecuteMsg::CollectAngelRewards {  } => execute_collect_rewards(deps, env, info),
+        ExecuteMsg::ClaimRewards { nft_id } => execute_claim_rewards(deps, env, info, nft_id),
+        ExecuteMsg::SetFeeBps { fee_bps } => execute_set_fee_bps(deps, env, info, fee_bps),
+        ExecuteMsg::Compound {} => execute_compound(deps, env, info),
+        ExecuteMsg::SetRewardMode { mode } => execute_set_reward_mode(deps, env, info, mode),
+        ExecuteMsg::SetUnbondStrategy { strategy } => execute_set_unbond_strategy(deps, env, info, strategy),
         ExecuteMsg::TransferBalanceToTreasury{  } => execute_transfer_balance(deps, env, info),
     }
 }
 
-pub fn execute_bond(deps: DepsMut, _env: Env, info: MessageInfo, nft_id: Uint128) -> Result<Response, ContractError> {
-    // let agent = AGENT.load(deps.storage)?;
-    // if info.sender != agent {
-    //     return Err(ContractError::Unauthorized {});
-    // }
-    // // Making sure there is only one coin and handling the possible errors.
-    // let d_coins = match one_coin(&info) {
-    //     Ok(coin) => coin,
-    //     Err(err) => {
-    //         match err {
-    //             PaymentError::NoFunds{} => {return Err(ContractError::NoFunds {  });}
-    //             PaymentError::MultipleDenoms{} => {return Err(ContractError::MultipleDenoms {  });}
-    //             _ => {return Err(ContractError::InvalidCoin {  });}
-    //         }
-    //     },
-    // };
-    // let amount = d_coins.amount;
-
-    let validator_address = chosen_validator(deps.as_ref(), None)?;
-
-    // // Update bonded tokens to validator
-    // let state = State::new();
-    // let mut validator_info = state.validator.load(deps.storage, &validator_address)?;
-    // validator_info.bonded.checked_add(amount.u128()).unwrap();
-    // state.validator.save(deps.storage, &validator_address, &validator_info)?;
-
-    // BONDED.update(deps.storage, |total| -> StdResult<_> {
-    //         Ok(total.checked_add(amount)?)
-    // })?;
-
-    // TOTAL_BONDED.update(deps.storage, |total| -> StdResult<_> {
-    //     Ok(total.checked_add(amount)?)
-    // })?;
-
-    let res = Response::new();
-    // let res = Response::new()
-    //     .add_message(StakingMsg::Delegate {
-    //         validator: validator_address.to_string(),
-    //         amount: d_coins,
-    //     })
-    //     .add_attribute("action", "bond")
-    //     .add_attribute("from", nft_id)
-    //     .add_attribute("bonded", amount)
-    //     .add_attribute("validator", validator_address);
+pub fn execute_bond(deps: DepsMut, env: Env, info: MessageInfo, nft_id: TokenId) -> Result<Response, ContractError> {
+    ensure_not_paused(deps.storage)?;
+    authorize_nft_action(deps.as_ref(), &info.sender, &nft_id, AgentAction::Bond)?;
+    // Making sure there is only one coin and handling the possible errors.
+    let d_coins = match one_coin(&info) {
+        Ok(coin) => coin,
+        Err(err) => {
+            match err {
+                PaymentError::NoFunds{} => {return Err(ContractError::NoFunds {  });}
+                PaymentError::MultipleDenoms{} => {return Err(ContractError::MultipleDenoms {  });}
+                _ => {return Err(ContractError::InvalidCoin {  });}
+            }
+        },
+    };
+    let amount = d_coins.amount;
+
+    // Returns the denomination that can be bonded (if there are multiple native tokens on the chain)
+    let can_be_bonded_denom = deps.querier.query_bonded_denom()?;
+    if d_coins.denom != can_be_bonded_denom {
+        return Err(ContractError::DenominationCanNotBeBonded { denom: d_coins.denom });
+    }
+
+    let validator_address = chosen_validator(deps.as_ref(), None, amount)?;
+
+    // Shares are minted at the current exchange rate, before the bonded amount changes
+    let shares = tokens_to_shares(amount, BONDED.load(deps.storage)?, TOTAL_SHARES.load(deps.storage)?, false)?;
+    if shares.is_zero() {
+        return Err(ContractError::InvalidZeroAmount {});
+    }
+
+    // Update shares to the nft. Rewards earned so far are credited before the shares change
+    let reward_index = REWARD_INDEX.load(deps.storage)?;
+    POSITIONS.update(deps.storage, &nft_id, |position| -> StdResult<_> {
+        let mut position = position.unwrap_or_else(|| NftPosition::new(env.block.time, reward_index));
+        position.settle_rewards(reward_index);
+        position.shares = position.shares.checked_add(shares)?;
+        Ok(position)
+    })?;
+
+    TOTAL_SHARES.update(deps.storage, |total| -> StdResult<_> {
+        Ok(total.checked_add(shares)?)
+    })?;
+
+    add_bonded(deps.storage, &validator_address, amount)?;
+
+    let res = Response::new()
+        .add_message(StakingMsg::Delegate {
+            validator: validator_address.to_string(),
+            amount: d_coins,
+        })
+        .add_attribute("action", "bond")
+        .add_attribute("from", nft_id)
+        .add_attribute("bonded", amount)
+        .add_attribute("validator", validator_address);
     Ok(res)
 }
 
+// Records tokens delegated to a validator
+fn add_bonded(storage: &mut dyn Storage, validator_address: &str, amount: Uint128) -> StdResult<()> {
+    let state = State::new();
+    let mut validator_info = state.validator.load(storage, validator_address)?;
+    validator_info.bonded = Uint128::from(validator_info.bonded).checked_add(amount)?.u128();
+    state.validator.save(storage, validator_address, &validator_info)?;
+
+    BONDED.update(storage, |total| -> StdResult<_> {
+            Ok(total.checked_add(amount)?)
+    })?;
+
+    TOTAL_BONDED.update(storage, |total| -> StdResult<_> {
+        Ok(total.checked_add(amount)?)
+    })?;
+    Ok(())
+}
+
+// Converts tokens into shares of BONDED. The first tokens bonded mint one share each.
+// Shares minted for a bond are rounded down, shares burnt for an unbond are rounded up.
+pub fn tokens_to_shares(amount: Uint128, bonded: Uint128, total_shares: Uint128, round_up: bool) -> StdResult<Uint128> {
+    if total_shares.is_zero() || bonded.is_zero() {
+        return Ok(amount);
+    }
+    let numerator = Uint256::from(amount) * Uint256::from(total_shares);
+    let denominator = Uint256::from(bonded);
+    let mut shares = numerator / denominator;
+    if round_up && shares * denominator != numerator {
+        shares += Uint256::one();
+    }
+    Ok(shares.try_into()?)
+}
+
+// Converts shares into the tokens of BONDED they are worth, rounded down
+pub fn shares_to_tokens(shares: Uint128, bonded: Uint128, total_shares: Uint128) -> Uint128 {
+    if total_shares.is_zero() {
+        return Uint128::zero();
+    }
+    shares.multiply_ratio(bonded, total_shares)
+}
 
-// Returns validator with the least amount of tokens bonded
+
+// Returns the validator furthest below its target share once amount is bonded.
+// The target share of a validator is its weight over the sum of the weights. Validators with weight zero are not chosen,
+// neither are validators out of the active set or charging more than MAX_COMMISSION, nor validators that would go past their max_bonded.
 // excluded address can not be returned 
-pub fn chosen_validator (deps: Deps, excluded_address: Option<String>) -> Result<String, ContractError>  {
+pub fn chosen_validator (deps: Deps, excluded_address: Option<String>, amount: Uint128) -> Result<String, ContractError>  {
     let state = State::new();
-    let validator_result : StdResult<Vec<_>>;
-    // if excluded_address.is_none() {
-    //     validator_result = state.validator.idx.bonded
-    //     .range(deps.storage,None,None,Order::Ascending)
-    //     .take(1)
-    //     .collect();
-    // } else {
-    //     let excluded_address = excluded_address.unwrap();
-    //     validator_result = state.validator.idx.bonded
-    //     .range(deps.storage,None,None,Order::Ascending)
-    //     .filter(|item| item.as_ref().unwrap().0 != excluded_address)
-    //     .take(1)
-    //     .collect();
-    // }
-
-        validator_result = state.validator.idx.bonded
+    let candidates : Vec<(String, ValidatorInfo)> = state.validator
         .range(deps.storage,None,None,Order::Ascending)
-        .take(1)
+        .filter(|item| match (item, &excluded_address) {
+            (Ok((address, _)), Some(excluded_address)) => address != excluded_address,
+            _ => true,
+        })
+        .collect::<StdResult<_>>()?;
+
+    if candidates.is_empty() {
+        return Err(ContractError::NoValidatorsRegistered {});
+    }
+
+    let active_validators = deps.querier.query_all_validators()?;
+    let max_commission = MAX_COMMISSION.load(deps.storage)?;
+    let candidates : Vec<(String, ValidatorInfo)> = candidates
+        .into_iter()
+        .filter(|(address, validator_info)| validator_info.weight > 0
+            && validator_status(&active_validators, address, max_commission).0 == ValidatorStatus::Healthy)
         .collect();
 
-    let vec_validator_address = validator_result?;
-    let validator_address = &vec_validator_address[0].0;
+    if candidates.is_empty() {
+        return Err(ContractError::NoValidatorAvailable {});
+    }
+
+    let candidates : Vec<(String, u128, u64)> = candidates
+        .into_iter()
+        .filter(|(_, validator_info)| validator_info.has_room_for(amount.u128()))
+        .map(|(address, validator_info)| (address, validator_info.bonded, validator_info.weight))
+        .collect();
 
-    Ok(validator_address.into())
+    furthest_below_target(&candidates, amount).ok_or(ContractError::ValidatorsFull { amount })
 }
 
+// Returns whether a validator can receive new bonds and its current commission, if it is in the active set
+fn validator_status(active_validators: &[Validator], address: &str, max_commission: Decimal) -> (ValidatorStatus, Option<Decimal>) {
+    match active_validators.iter().find(|validator| validator.address == address) {
+        None => (ValidatorStatus::Inactive, None),
+        Some(validator) if validator.commission > max_commission => (ValidatorStatus::CommissionTooHigh, Some(validator.commission)),
+        Some(validator) => (ValidatorStatus::Healthy, Some(validator.commission)),
+    }
+}
 
-pub fn execute_unbond(deps: DepsMut, env: Env, info: MessageInfo, nft_id: Uint128, amount: Uint128) -> Result<Response, ContractError> {
-    let agent = AGENT.load(deps.storage)?;
-    if info.sender != agent {
-        return Err(ContractError::Unauthorized {});
+// Picks, out of (address, bonded, weight), the validator whose bonded is furthest below its weighted share of
+// the candidates total bonded plus amount. Ties go to the first candidate.
+fn furthest_below_target(candidates: &[(String, u128, u64)], amount: Uint128) -> Option<String> {
+    let total_weight : u64 = candidates.iter().map(|(_, _, weight)| weight).sum();
+    let total_bonded = candidates
+        .iter()
+        .fold(Uint256::from(amount), |acc, (_, bonded, _)| acc + Uint256::from(*bonded));
+
+    // deficit_i = total_bonded * weight_i / total_weight - bonded_i
+    // deficit_i > deficit_j  <=>  total_bonded * weight_i + bonded_j * total_weight > total_bonded * weight_j + bonded_i * total_weight
+    let mut chosen : Option<&(String, u128, u64)> = None;
+    for candidate in candidates {
+        let is_further = match chosen {
+            None => true,
+            Some(best) => {
+                total_bonded * Uint256::from(candidate.2) + Uint256::from(best.1) * Uint256::from(total_weight)
+                    > total_bonded * Uint256::from(best.2) + Uint256::from(candidate.1) * Uint256::from(total_weight)
+            }
+        };
+        if is_further {
+            chosen = Some(candidate);
+        }
     }
-    // Returns the denomination that can be bonded (if there are multiple native tokens on the chain)
-    let can_be_bonded_denom = deps.querier.query_bonded_denom()?;
+    chosen.map(|(address, _, _)| address.clone())
+}
 
-    let total_number_validators = NUMBER_VALIDATORS.load(deps.storage)?;
-    let number_validators= calc_validator_number(total_number_validators, amount)?;
-    
-    let vec_address_coin = chosen_validators_unstake(deps.as_ref(), amount, can_be_bonded_denom, number_validators)?;
 
-    // Turn Vec<String, Coin> into Vec<StakingMsg>
-    let msgs : Vec<StakingMsg> = vec_address_coin
-    .clone()
-    .into_iter()
-    .map(|item| StakingMsg::Undelegate { validator: item.0, amount: item.1 })
-    .collect();
+pub fn execute_unbond(deps: DepsMut, env: Env, info: MessageInfo, nft_id: TokenId, amount: Uint128) -> Result<Response, ContractError> {
+    ensure_not_paused(deps.storage)?;
+    authorize_nft_action(deps.as_ref(), &info.sender, &nft_id, AgentAction::Unbond)?;
 
-    let state = State::new();
-    for i in 0..vec_address_coin.len()-1 {
-        // Remove from the validator info the required amount
-        let mut validator_info = state.validator.load(deps.storage, &vec_address_coin[i].0)?;
-        validator_info.bonded.checked_sub(vec_address_coin[i].1.amount.u128()).unwrap();
-        state.validator.save(deps.storage,&vec_address_coin[i].0,&validator_info)?;
+    // An NFT can not unbond more than it has bonded
+    let mut position = POSITIONS.may_load(deps.storage, &nft_id)?
+        .unwrap_or_else(|| NftPosition::new(env.block.time, Decimal::zero()));
+    let bonded = BONDED.load(deps.storage)?;
+    let total_shares = TOTAL_SHARES.load(deps.storage)?;
+    let position_bonded = shares_to_tokens(position.shares, bonded, total_shares);
+    if position_bonded < amount {
+        return Err(ContractError::UnbondExceedsPosition { nft_id: nft_id.clone(), bonded: position_bonded, amount });
+    }
+    // Unbonding everything burns every share, rounding could leave dust shares worth nothing
+    let shares = if amount == position_bonded {
+        position.shares
+    } else {
+        tokens_to_shares(amount, bonded, total_shares, true)?
+    };
 
-        CLAIMS.create_claim(
-            deps.storage,
-            &Addr::unchecked(nft_id.to_string()),
-            vec_address_coin[i].1.amount,
-            validator_info.unbonding_period.after(&env.block),  
-        )?;
+    if shares.is_zero() {
+        return Err(ContractError::InvalidZeroAmount {});
     }
 
-    // If all validators have got the same unbonding_period. One single entry to CLAIMS could be done
-    // CLAIMS.create_claim(
-    //     deps.storage,
-    //     &Addr::unchecked(nft_id.to_string()),
-    //     amount,
-    //     unbonding_period.after(&env.block),  
-    // )?;
+    position.settle_rewards(REWARD_INDEX.load(deps.storage)?);
+    position.shares -= shares;
+    POSITIONS.save(deps.storage, &nft_id, &position)?;
 
+    TOTAL_SHARES.update(deps.storage, |total| -> StdResult<_> {
+        Ok(total.checked_sub(shares)?)
+    })?;
 
     BONDED.update(deps.storage, |total| -> StdResult<_> {
         Ok(total.checked_sub(amount)?)
@@ -198,129 +457,194 @@ pub fn execute_unbond(deps: DepsMut, env: Env, info: MessageInfo, nft_id: Uint12
         Ok(total.checked_add(amount)?)
     })?;  
 
+    // The tokens are undelegated with the rest of the pending batch by ProcessUnbondBatch
+    let batch_id = PENDING_BATCH.load(deps.storage)?;
+    let mut batch = UNBOND_BATCHES.load(deps.storage, batch_id)?;
+    batch.total = batch.total.checked_add(amount)?;
+    UNBOND_BATCHES.save(deps.storage, batch_id, &batch)?;
+    UNBOND_CLAIMS.update(deps.storage, (&nft_id, batch_id), |claim| -> StdResult<_> {
+        Ok(claim.unwrap_or_default().checked_add(amount)?)
+    })?;
+
     let res = Response::new()
-        .add_messages(msgs)
         .add_attribute("action", "unbond")
         .add_attribute("from", nft_id)
-        .add_attribute("unbonded", amount);
+        .add_attribute("unbonded", amount)
+        .add_attribute("batch_id", batch_id.to_string());
     Ok(res)
 }
 
+// Undelegates the pending batch once UNBOND_EPOCH has elapsed since it started and opens a new one
+pub fn execute_process_unbond_batch(mut deps: DepsMut, env: Env, info: MessageInfo) -> Result<Response, ContractError> {
+    if !is_agent(deps.storage, &info.sender, AgentAction::Unbond)? {
+        return Err(ContractError::Unauthorized {});
+    }
 
-// It returns a vector with (validator_address, Coin) with information about the unstake about to happen. 
-// PLAN_A: amount is split between the first 'number_validator' with more coin 'bonded'....
-// PLAN_B: validators ordered Descending by bonded. Start unbonding all the coins from the first until we get 'amount'
-// Confirms that the sum of the split_amount from selected validators is equal to amount
-pub fn chosen_validators_unstake (deps: Deps, amount:Uint128, denom:String, number_validators: u64) -> Result<Vec<(String, Coin)>, ContractError>  {
-    let limit = number_validators as usize;
-    let amount_to_split = amount / Uint128::from(number_validators);
-    let state = State::new();
-    
-    // Validators/Coin(amount, denom) from which we are going to unstake as vector<addr,Coin>
-    let plana_validator_result : StdResult<Vec<(String, Coin)>> = state.validator.idx.bonded
-    .range(deps.storage,None,None,Order::Descending)
-    .filter(|item| 
-        item.as_ref().unwrap().1.bonded > amount_to_split.u128() && item.as_ref().unwrap().1.bond_denom == denom)
-    .map(|item|
-        Ok((item.unwrap().0, coin(amount_to_split.u128(), &denom))))
-    .take(limit)
-    .collect();
-
-    let count = plana_validator_result.as_ref().unwrap().len();
-
-    let vec_address_coin:Vec<(String, Coin)> = if count != limit {
-        let all_validators : StdResult<Vec<(String, Coin)>> = state.validator.idx.bonded
-        .range(deps.storage,None,None,Order::Descending)
-        .map(|item|
-                Ok((item.unwrap().0, coin(amount_to_split.u128(), &denom)))
-            )
-        .collect();
+    let batch_id = PENDING_BATCH.load(deps.storage)?;
+    let mut batch = UNBOND_BATCHES.load(deps.storage, batch_id)?;
+    let ready_at = batch.started_at.plus_seconds(UNBOND_EPOCH.load(deps.storage)?);
+    if env.block.time < ready_at {
+        return Err(ContractError::UnbondEpochNotElapsed { ready_at });
+    }
+    if batch.total.is_zero() {
+        return Err(ContractError::EmptyUnbondBatch {});
+    }
 
-        let vec_all_validators = all_validators?;
+    // Without enough free unbonding entries the batch keeps collecting requests until they free up
+    let (msgs, release_at) = undelegate(deps.branch(), &env, batch.total)?
+        .ok_or(ContractError::NoUnbondingEntryAvailable { amount: batch.total })?;
 
-        let mut remaining_amount = amount.clone();
-        let total_number_validators = vec_all_validators.len();
-        let total_number_validators_u64 = total_number_validators as u64;
-        let mut i = 0;
-        let mut vec_planb_validator : Vec<(String, Coin)> = vec![];
+    batch.release_at = Some(release_at);
+    UNBOND_BATCHES.save(deps.storage, batch_id, &batch)?;
+    PENDING_BATCH.save(deps.storage, &(batch_id + 1))?;
+    UNBOND_BATCHES.save(deps.storage, batch_id + 1, &UnbondBatch::new(env.block.time))?;
 
-        while remaining_amount > Uint128::zero() {
+    Ok(Response::new()
+        .add_messages(msgs)
+        .add_attribute("action", "process_unbond_batch")
+        .add_attribute("batch_id", batch_id.to_string())
+        .add_attribute("undelegated", batch.total)
+        .add_attribute("release_at", release_at.to_string()))
+}
 
-            if i > total_number_validators - 1 {
-                return Err(ContractError::UnableUnstakeAmount {
-                    amount: amount, number_validators: Uint64::from(total_number_validators_u64)
-                });
-            }
+// Undelegates amount from the validators with a free unbonding entry. Returns the messages and when the last
+// undelegation matures, or None, without touching the state, when those validators have not got enough bonded.
+fn undelegate(deps: DepsMut, env: &Env, amount: Uint128) -> Result<Option<(Vec<StakingMsg>, Expiration)>, ContractError> {
+    // Returns the denomination that can be bonded (if there are multiple native tokens on the chain)
+    let can_be_bonded_denom = deps.querier.query_bonded_denom()?;
 
-            let address = &vec_all_validators[i].0;
-            let denom = &vec_all_validators[i].1.denom;
-            let validator_amount = &vec_all_validators[i].1.amount.u128();
+    let vec_address_coin = match chosen_validators_unstake(deps.as_ref(), &env.block, amount, can_be_bonded_denom) {
+        Err(ContractError::UnableUnstakeAmount { .. }) => return Ok(None),
+        vec_address_coin => vec_address_coin?,
+    };
 
-            if remaining_amount > vec_all_validators[i].1.amount {
-                vec_planb_validator.push((address.to_string(),coin(*validator_amount, denom)));
-                remaining_amount = remaining_amount - vec_all_validators[i].1.amount;
-                i +=1;
-            } else {
-                vec_planb_validator.push((address.to_string(),coin(remaining_amount.u128(), denom)));
-                break;
-            }
+    let state = State::new();
+    let mut msgs = vec![];
+    let mut last_release_at : Option<Expiration> = None;
+    for (validator_address, coin) in vec_address_coin {
+        // Move the undelegated amount from bonded to claimed on the validator info
+        let mut validator_info = state.validator.load(deps.storage, &validator_address)?;
+        validator_info.bonded = Uint128::from(validator_info.bonded).checked_sub(coin.amount)?.u128();
+        validator_info.claimed = Uint128::from(validator_info.claimed).checked_add(coin.amount)?.u128();
+        state.validator.save(deps.storage, &validator_address, &validator_info)?;
+
+        let release_at = validator_info.unbonding_period.after(&env.block);
+        let mut entries = unbonding_entries(deps.storage, &env.block, &validator_address)?;
+        entries.push(release_at);
+        UNBONDING_ENTRIES.save(deps.storage, &validator_address, &entries)?;
+        if last_release_at.is_none_or(|last| release_at > last) {
+            last_release_at = Some(release_at);
         }
-        vec_planb_validator
-    } else {
-        plana_validator_result?
-    };
 
-    let sum : u128 = vec_address_coin
-    .iter()
-    .map(|item| item.1.amount.u128())
-    .sum();
+        msgs.push(StakingMsg::Undelegate { validator: validator_address, amount: coin });
+    }
+    Ok(last_release_at.map(|release_at| (msgs, release_at)))
+}
+
+// Unbonding entries of the contract on a validator that have not matured yet
+fn unbonding_entries(storage: &dyn Storage, block: &BlockInfo, address: &str) -> StdResult<Vec<Expiration>> {
+    let entries = UNBONDING_ENTRIES.may_load(storage, address)?.unwrap_or_default();
+    Ok(entries.into_iter().filter(|release_at| !release_at.is_expired(block)).collect())
+}
 
-    // Confirm the vector takes into account exactly the amount required
-    if sum != amount.u128() {
-        return Err(ContractError::UnableUnstakeAmount {
-            amount: amount, number_validators: Uint64::from(number_validators)
-        });
+// It returns a vector with (validator_address, Coin) with information about the unstake about to happen.
+// amount is split between the validators bonded in denom following UNBOND_STRATEGY.
+// Validators with MAX_UNBONDING_ENTRIES pending are left out, the chain would reject one more undelegation
+pub fn chosen_validators_unstake (deps: Deps, block: &BlockInfo, amount:Uint128, denom:String) -> Result<Vec<(String, Coin)>, ContractError>  {
+    let state = State::new();
+    let mut validators : Vec<(String, u128)> = vec![];
+    for item in state.validator.range(deps.storage,None,None,Order::Ascending) {
+        let (address, validator_info) = item?;
+        if validator_info.bonded > 0 && validator_info.bond_denom == denom
+            && unbonding_entries(deps.storage, block, &address)?.len() < MAX_UNBONDING_ENTRIES {
+            validators.push((address, validator_info.bonded));
+        }
     }
 
-     Ok(vec_address_coin)
+    let strategy = UNBOND_STRATEGY.load(deps.storage)?;
+    let split = split_unbond(&strategy, &validators, amount.u128())
+        .ok_or(ContractError::UnableUnstakeAmount {
+            amount, number_validators: Uint64::from(validators.len() as u64)
+        })?;
+
+    Ok(split
+        .into_iter()
+        .map(|(address, amount)| (address, coin(amount, &denom)))
+        .collect())
 }
 
+// Splits amount between validators given as (address, bonded). Every validator gets at most its bonded and
+// the parts always sum to amount. Validators getting nothing are left out. None if amount is above the total bonded.
+pub fn split_unbond(strategy: &UnbondStrategy, validators: &[(String, u128)], amount: u128) -> Option<Vec<(String, u128)>> {
+    let total_bonded = validators
+        .iter()
+        .try_fold(0u128, |acc, (_, bonded)| acc.checked_add(*bonded))?;
+    if amount > total_bonded {
+        return None;
+    }
 
-// Calculates how many validators are going to be unstaken from. 
-// At least one token has to be unstaked per validator 
-pub fn calc_validator_number(number_validators: Uint64, amount: Uint128) -> StdResult<u64> {
-    // Possible number of validators to split the bond is defined by the next vector. 
-    // Powers of two, five or product of both to avoid repeating decimals on the amount to split between validators
-    let v = vec![1, 2, 4, 5, 8, 10];  // 16, 20, 25, 32, 40, 50, 64, 80, 100
+    // Largest bonded first, ties by address
+    let mut validators = validators.to_vec();
+    validators.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    let mut parts = vec![0u128; validators.len()];
 
-    let mut i = v.len();
-    while i>1 {
-        // At least one token to unbond per validator
-        if v[i] <= number_validators.u64() && amount > Uint128::from(number_validators){
-            return Ok(v[i]);
+    match strategy {
+        UnbondStrategy::Proportional if total_bonded > 0 => {
+            for (part, (_, bonded)) in parts.iter_mut().zip(&validators) {
+                *part = Uint128::new(amount).multiply_ratio(*bonded, total_bonded).u128();
+            }
+        }
+        UnbondStrategy::Proportional | UnbondStrategy::LargestFirst => {}
+        UnbondStrategy::EvenSplit => {
+            // Smallest first, so whatever a validator can not take is spread over the larger ones
+            let mut remaining = amount;
+            for (i, (part, (_, bonded))) in parts.iter_mut().zip(&validators).enumerate().rev() {
+                *part = (*bonded).min(remaining / (i as u128 + 1));
+                remaining -= *part;
+            }
         }
-        i= i.checked_sub(1).unwrap();
     }
 
-    Ok(1)
+    // What the strategy left because of rounding is taken from the largest validators first
+    let mut remaining = amount - parts.iter().sum::<u128>();
+    for (part, (_, bonded)) in parts.iter_mut().zip(&validators) {
+        let taken = (bonded - *part).min(remaining);
+        *part += taken;
+        remaining -= taken;
+    }
+
+    Some(validators
+        .into_iter()
+        .zip(parts)
+        .filter(|(_, part)| *part > 0)
+        .map(|((address, _), part)| (address, part))
+        .collect())
 }
 
-pub fn execute_claim(deps: DepsMut, env: Env, info: MessageInfo, nft_id: Uint128, sender: String) -> Result<Response, ContractError> {
-    let agent = AGENT.load(deps.storage)?;
-    if info.sender != agent {
-        return Err(ContractError::Unauthorized {});
-    }
+// Sends the matured claims of an NFT to its current owner
+pub fn execute_claim(deps: DepsMut, env: Env, info: MessageInfo, nft_id: TokenId) -> Result<Response, ContractError> {
+    authorize_nft_action(deps.as_ref(), &info.sender, &nft_id, AgentAction::Claim)?;
 
-    let sender = deps.api.addr_validate(&sender)?;
+    let owner = nft_owner(deps.as_ref(), &nft_id)?;
     let can_be_bonded_denom = deps.querier.query_bonded_denom()?;
     let mut balance = deps
         .querier
         .query_balance(&env.contract.address, &can_be_bonded_denom)?;
 
-    let to_send =
-        CLAIMS.claim_tokens(deps.storage, &Addr::unchecked(nft_id), &env.block, None)?;
+    // Only the batches undelegated and released can be claimed
+    let mut to_send = Uint128::zero();
+    for (batch_id, amount) in matured_claims(deps.as_ref(), &env.block, &nft_id)? {
+        UNBOND_CLAIMS.remove(deps.storage, (&nft_id, batch_id));
+        to_send += amount;
+    }
+
+    // An escrowed NFT goes back to its depositor once nothing is bonded or left to claim for it
+    let release = match ESCROWED_NFTS.may_load(deps.storage, &nft_id)? {
+        Some(depositor) if is_fully_unbonded(deps.as_ref(), &nft_id)? => Some(depositor),
+        _ => None,
+    };
 
-    if to_send == Uint128::zero() {
+    if to_send == Uint128::zero() && release.is_none() {
         return Err(ContractError::NothingToClaim {});
     }
 
@@ -332,21 +656,150 @@ pub fn execute_claim(deps: DepsMut, env: Env, info: MessageInfo, nft_id: Uint128
         Ok(total.checked_sub(to_send)?)
     })?;  
 
-    // transfer tokens to the sender
-    balance.amount = to_send;
-    let res = Response::new()
-        .add_message(BankMsg::Send {
-            to_address: sender.to_string(),
-            amount: vec![balance],
-        })
+    let mut res = Response::new()
         .add_attribute("action", "claim")
-        .add_attribute("from", sender)
-        .add_attribute("nft_id", nft_id.to_string())
+        .add_attribute("owner", owner.clone())
+        .add_attribute("nft_id", nft_id.clone())
         .add_attribute("amount", to_send);
+
+    // transfer tokens to the owner
+    if !to_send.is_zero() {
+        balance.amount = to_send;
+        res = res.add_message(BankMsg::Send {
+            to_address: owner.to_string(),
+            amount: vec![balance],
+        });
+    }
+
+    if let Some(depositor) = release {
+        ESCROWED_NFTS.remove(deps.storage, &nft_id);
+        res = res
+            .add_message(WasmMsg::Execute {
+                contract_addr: NFT_CONTRACT.load(deps.storage)?.to_string(),
+                msg: to_binary(&Cw721ExecuteMsg::TransferNft { recipient: depositor.to_string(), token_id: nft_id.clone() })?,
+                funds: vec![],
+            })
+            .add_attribute("released_to", depositor);
+    }
     Ok(res)
 }
 
-pub fn execute_add_validator(deps: DepsMut, _env: Env, info: MessageInfo, validator_address: String, bond_denom: String, unbonding_period: Duration) -> Result<Response, ContractError> {
+fn is_fully_unbonded(deps: Deps, nft_id: &str) -> StdResult<bool> {
+    let shares = POSITIONS.may_load(deps.storage, nft_id)?.map(|position| position.shares).unwrap_or_default();
+    // Shares worth no token can not be unbonded any more
+    let bonded = shares_to_tokens(shares, BONDED.load(deps.storage)?, TOTAL_SHARES.load(deps.storage)?);
+    let has_claims = UNBOND_CLAIMS.prefix(nft_id).keys(deps.storage, None, None, Order::Ascending).next().is_some();
+    Ok(bonded.is_zero() && !has_claims)
+}
+
+// Escrows an NFT sent with SendNft. Its depositor bonds for it with Bond, in the same transaction or later,
+// and gets it back with the Claim that leaves nothing bonded or to claim for it
+pub fn execute_receive_nft(deps: DepsMut, _env: Env, info: MessageInfo, msg: Cw721ReceiveMsg) -> Result<Response, ContractError> {
+    ensure_not_paused(deps.storage)?;
+    if info.sender != NFT_CONTRACT.load(deps.storage)? {
+        return Err(ContractError::Unauthorized {});
+    }
+    let depositor = deps.api.addr_validate(&msg.sender)?;
+    let ReceiveNftMsg::Stake {} = from_binary(&msg.msg)?;
+
+    ESCROWED_NFTS.save(deps.storage, &msg.token_id, &depositor)?;
+
+    Ok(Response::new()
+        .add_attribute("action", "receive_nft")
+        .add_attribute("nft_id", msg.token_id)
+        .add_attribute("depositor", depositor))
+}
+
+// An agent allowed to do action can act for every NFT and the depositor for its escrowed NFT.
+// In self service mode, the owner of an NFT can act for it too
+fn authorize_nft_action(deps: Deps, sender: &Addr, nft_id: &str, action: AgentAction) -> Result<(), ContractError> {
+    if is_agent(deps.storage, sender, action)? {
+        return Ok(());
+    }
+    let authorized = match ESCROWED_NFTS.may_load(deps.storage, nft_id)? {
+        Some(depositor) => *sender == depositor,
+        None => SELF_SERVICE.load(deps.storage)? && *sender == nft_owner(deps, nft_id)?,
+    };
+    if !authorized {
+        return Err(ContractError::Unauthorized {});
+    }
+    Ok(())
+}
+
+// Whether sender is in the agent set with the permission for action
+fn is_agent(storage: &dyn Storage, sender: &Addr, action: AgentAction) -> StdResult<bool> {
+    Ok(AGENTS.may_load(storage, sender)?.is_some_and(|permissions| permissions.allows(action)))
+}
+
+// Current owner of nft_id in the NFT_CONTRACT collection, or its depositor while escrowed here.
+// Claims and rewards of an NFT go to its owner
+fn nft_owner(deps: Deps, nft_id: &str) -> StdResult<Addr> {
+    if let Some(depositor) = ESCROWED_NFTS.may_load(deps.storage, nft_id)? {
+        return Ok(depositor);
+    }
+    let nft_contract = NFT_CONTRACT.load(deps.storage)?;
+    let res: OwnerOfResponse = deps.querier.query_wasm_smart(
+        nft_contract,
+        &Cw721QueryMsg::OwnerOf { token_id: nft_id.to_string(), include_expired: None },
+    )?;
+    deps.api.addr_validate(&res.owner)
+}
+
+// Moves the legacy claims, created per undelegation before unbond batches, to UNBOND_CLAIMS.
+// Every release time of the legacy claims becomes an undelegated batch, followed by the pending batch.
+pub fn migrate_legacy_claims(storage: &mut dyn Storage, now: Timestamp) -> StdResult<usize> {
+    let legacy : Vec<(TokenId, Vec<Claim>)> = LEGACY_CLAIMS
+        .range(storage, None, None, Order::Ascending)
+        .collect::<StdResult<_>>()?;
+    if legacy.is_empty() {
+        return Ok(0);
+    }
+    if PENDING_BATCH.may_load(storage)?.is_some() {
+        return Err(StdError::generic_err("Legacy claims can not be migrated next to unbond batches"));
+    }
+
+    let mut batches : Vec<UnbondBatch> = vec![];
+    for (nft_id, claims) in &legacy {
+        for claim in claims {
+            let batch_id = match batches.iter().position(|batch| batch.release_at == Some(claim.release_at)) {
+                Some(batch_id) => batch_id,
+                None => {
+                    batches.push(UnbondBatch { total: Uint128::zero(), started_at: now, release_at: Some(claim.release_at) });
+                    batches.len() - 1
+                }
+            };
+            batches[batch_id].total += claim.amount;
+            UNBOND_CLAIMS.update(storage, (nft_id, batch_id as u64), |amount| -> StdResult<_> {
+                Ok(amount.unwrap_or_default() + claim.amount)
+            })?;
+        }
+        LEGACY_CLAIMS.remove(storage, nft_id);
+    }
+
+    for (batch_id, batch) in batches.iter().enumerate() {
+        UNBOND_BATCHES.save(storage, batch_id as u64, batch)?;
+    }
+    PENDING_BATCH.save(storage, &(batches.len() as u64))?;
+    UNBOND_BATCHES.save(storage, batches.len() as u64, &UnbondBatch::new(now))?;
+
+    Ok(legacy.len())
+}
+
+// Claims of nft_id, as (batch_id, amount), whose batch has been undelegated and released
+fn matured_claims(deps: Deps, block: &BlockInfo, nft_id: &str) -> StdResult<Vec<(u64, Uint128)>> {
+    let mut matured = vec![];
+    for item in UNBOND_CLAIMS.prefix(nft_id).range(deps.storage, None, None, Order::Ascending) {
+        let (batch_id, amount) = item?;
+        let batch = UNBOND_BATCHES.load(deps.storage, batch_id)?;
+        if batch.release_at.is_some_and(|release_at| release_at.is_expired(block)) {
+            matured.push((batch_id, amount));
+        }
+    }
+    Ok(matured)
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn execute_add_validator(deps: DepsMut, _env: Env, info: MessageInfo, validator_address: String, bond_denom: String, unbonding_period: Duration, weight: Option<u64>, max_bonded: Option<Uint128>) -> Result<Response, ContractError> {
     let manager = MANAGER.load(deps.storage)?;
 
     if info.sender != manager {
@@ -381,6 +834,8 @@ pub fn execute_add_validator(deps: DepsMut, _env: Env, info: MessageInfo, valida
         unbonding_period,
         bonded: 0u128,
         claimed: 0u128,
+        weight: weight.unwrap_or(DEFAULT_VALIDATOR_WEIGHT),
+        max_bonded: max_bonded.map(|max_bonded| max_bonded.u128()),
     };
 
     state.validator.save(deps.storage, &validator_address, &validator_info)?;
@@ -407,41 +862,113 @@ pub fn execute_remove_validator(deps: DepsMut, env: Env, info: MessageInfo, src_
         return Err(ContractError::NotRegisteredValidator { address:src_validator_address });
     }
 
-     let validator_count : u128 = state.validator.idx.bonded
-    .range(deps.storage, None, None, Order::Descending)
-    .into_iter()
-    .count().try_into().unwrap();
-
     let option_full_delegation = deps.querier.query_delegation(env.contract.address,src_validator_address.clone())?;
-    // What if the chosen validator is the one we are trying to remove??
-    let dst_validator_address = chosen_validator(deps.as_ref(), Some(src_validator_address.clone()))?;
+    // Delegations are moved to the validator chosen as for a bond, the removed one excluded
+    let dst_validator_address = match &option_full_delegation {
+        Some(full_delegation) => match chosen_validator(deps.as_ref(), Some(src_validator_address.clone()), full_delegation.amount.amount) {
+            Err(ContractError::NoValidatorsRegistered {}) => {
+                return Err(ContractError::CustomError { val: "Only one validator registered. Its delegations can not be redelegated".to_string() })
+            }
+            dst_validator_address => Some(dst_validator_address?),
+        },
+        None => None,
+    };
 
     state.validator.remove(deps.storage, &src_validator_address)?;
-    let res:Response;
-    if option_full_delegation.is_some() && validator_count ==1 {
-        return Err(ContractError::CustomError { val: "Only one validator registered. Its delegations can not be redelegated".to_string() })
-    } else if option_full_delegation.is_some(){
-        let amount = option_full_delegation.unwrap().amount;
-        // When we redelegate, by default all the pending rewards are claimed.
-        let msg = StakingMsg::Redelegate { 
-            src_validator:src_validator_address.to_string(), 
-            dst_validator: dst_validator_address.clone(), 
-            amount: amount.clone() 
-        };
 
-        res = Response::new()
-        .add_message(msg)
-        .add_attribute("action", "remove_validator")
-        .add_attribute("address",src_validator_address)
-        .add_attribute("redelegated_validator", dst_validator_address)
-        .add_attribute("redelegated_denom", amount.denom)
-        .add_attribute("redelegated_amount", amount.amount);
+    // Matured entries are of no use any more, the ones still unbonding are kept in case the validator is added back
+    let entries = unbonding_entries(deps.storage, &env.block, &src_validator_address)?;
+    if entries.is_empty() {
+        UNBONDING_ENTRIES.remove(deps.storage, &src_validator_address);
     } else {
-        res = Response::new()
-        .add_attribute("action", "remove_validator")
-        .add_attribute("address",src_validator_address)
+        UNBONDING_ENTRIES.save(deps.storage, &src_validator_address, &entries)?;
+    }
+
+    NUMBER_VALIDATORS.update(deps.storage, |total| -> StdResult<_> {
+        Ok(total.checked_sub(Uint64::from(1u64))?)
+    })?;
+
+    let res = match (option_full_delegation, dst_validator_address) {
+        (Some(full_delegation), Some(dst_validator_address)) => {
+            let amount = full_delegation.amount;
+
+            // The destination gets what is redelegated on chain, which can differ from what was recorded on the removed validator
+            let mut dst_validator_info = state.validator.load(deps.storage, &dst_validator_address)?;
+            dst_validator_info.bonded = Uint128::from(dst_validator_info.bonded).checked_add(amount.amount)?.u128();
+            state.validator.save(deps.storage, &dst_validator_address, &dst_validator_info)?;
+
+            // When we redelegate, by default all the pending rewards are claimed.
+            let msg = StakingMsg::Redelegate { 
+                src_validator:src_validator_address.to_string(), 
+                dst_validator: dst_validator_address.clone(), 
+                amount: amount.clone() 
+            };
+
+            Response::new()
+            .add_message(msg)
+            .add_attribute("action", "remove_validator")
+            .add_attribute("address",src_validator_address)
+            .add_attribute("redelegated_validator", dst_validator_address)
+            .add_attribute("redelegated_denom", amount.denom)
+            .add_attribute("redelegated_amount", amount.amount)
+        }
+        _ => Response::new()
+            .add_attribute("action", "remove_validator")
+            .add_attribute("address",src_validator_address),
+    };
+    Ok(res)
+}
+
+pub fn execute_set_max_commission(deps: DepsMut, _env: Env, info: MessageInfo, max_commission: Decimal) -> Result<Response, ContractError> {
+    let manager = MANAGER.load(deps.storage)?;
+    if info.sender != manager {
+        return Err(ContractError::Unauthorized {});
+    }
+    if max_commission > Decimal::one() {
+        return Err(ContractError::InvalidMaxCommission { max_commission });
+    }
+    MAX_COMMISSION.save(deps.storage, &max_commission)?;
+
+    Ok(Response::new()
+        .add_attribute("action", "set_max_commission")
+        .add_attribute("max_commission", max_commission.to_string()))
+}
+
+pub fn execute_set_validator_weight(deps: DepsMut, _env: Env, info: MessageInfo, address: String, weight: u64) -> Result<Response, ContractError> {
+    let manager = MANAGER.load(deps.storage)?;
+    if info.sender != manager {
+        return Err(ContractError::Unauthorized {});
+    }
+
+    let state = State::new();
+    let mut validator_info = state.validator.may_load(deps.storage, &address)?
+        .ok_or_else(|| ContractError::NotRegisteredValidator { address: address.clone() })?;
+    validator_info.weight = weight;
+    state.validator.save(deps.storage, &address, &validator_info)?;
+
+    Ok(Response::new()
+        .add_attribute("action", "set_validator_weight")
+        .add_attribute("address", address)
+        .add_attribute("weight", weight.to_string()))
+}
+
+pub fn execute_set_validator_max_bonded(deps: DepsMut, _env: Env, info: MessageInfo, address: String, max_bonded: Option<Uint128>) -> Result<Response, ContractError> {
+    let manager = MANAGER.load(deps.storage)?;
+    if info.sender != manager {
+        return Err(ContractError::Unauthorized {});
     }
-     Ok(res)
+
+    let state = State::new();
+    let mut validator_info = state.validator.may_load(deps.storage, &address)?
+        .ok_or_else(|| ContractError::NotRegisteredValidator { address: address.clone() })?;
+    // A cap below the current bonded only stops new bonds, nothing is undelegated
+    validator_info.max_bonded = max_bonded.map(|max_bonded| max_bonded.u128());
+    state.validator.save(deps.storage, &address, &validator_info)?;
+
+    Ok(Response::new()
+        .add_attribute("action", "set_validator_max_bonded")
+        .add_attribute("address", address)
+        .add_attribute("max_bonded", max_bonded.map_or("none".to_string(), |max_bonded| max_bonded.to_string())))
 }
 
 // Check if chain delegated tokens by this contract match the value registered in TOTAL_BONDED state
@@ -455,15 +982,112 @@ pub fn execute_bond_check (deps: Deps, env:Env, info: MessageInfo) -> Result<Res
     // Expecting all delegations to be of the same denom
     let total_bonded = get_all_bonded(&deps.querier, &env.contract.address)?;
 
-    let state_total_bonded = BONDED.load(deps.storage)?;
+    // Unbonds of the pending batch are still delegated until ProcessUnbondBatch
+    let pending_batch = PENDING_BATCH.load(deps.storage)?;
+    let pending_unbond = UNBOND_BATCHES.load(deps.storage, pending_batch)?.total;
+    let state_total_bonded = BONDED.load(deps.storage)?.checked_add(pending_unbond)?;
     if total_bonded != state_total_bonded {
         return Err(ContractError::BondedDiffer {
-            total_bonded: total_bonded, state_total_bonded: state_total_bonded
+            total_bonded, state_total_bonded
         });       
     } 
     Ok(Response::default())
 }
 
+// Validators delegated less than their bonded have been slashed. The loss is split between BONDED and the pending
+// unbond batch by their stake: the shares of every NFT are worth less and the claims of the unbond batches shrink
+pub fn execute_sync_slashing(deps: DepsMut, env: Env, info: MessageInfo) -> Result<Response, ContractError> {
+    let manager = MANAGER.load(deps.storage)?;
+    if info.sender != manager {
+        return Err(ContractError::Unauthorized {});
+    }
+
+    let state = State::default();
+    let validators : Vec<(String, ValidatorInfo)> = state.validator
+        .range(deps.storage, None, None, Order::Ascending)
+        .collect::<StdResult<_>>()?;
+    let mut res = Response::new();
+    let mut slashed = Uint128::zero();
+    for (address, mut validator_info) in validators {
+        let delegated = bonded_on_validator(&deps.querier, &env.contract.address, &Addr::unchecked(&address))?;
+        if delegated.u128() >= validator_info.bonded {
+            continue;
+        }
+        let loss = Uint128::new(validator_info.bonded) - delegated;
+        validator_info.bonded = delegated.u128();
+        state.validator.save(deps.storage, &address, &validator_info)?;
+        slashed += loss;
+        res = res.add_event(Event::new("slash")
+            .add_attribute("validator", address)
+            .add_attribute("amount", loss)
+            .add_attribute("bonded", delegated));
+    }
+
+    // The pending batch is still delegated, its claims take their share of the loss. The chain slashes the
+    // undelegations in flight too, but they can not be queried: the batches not released yet are written down
+    // by the same share of their total, as if they were still delegated
+    let pending_batch = PENDING_BATCH.load(deps.storage)?;
+    let bonded = BONDED.load(deps.storage)?;
+    let pending_total = UNBOND_BATCHES.load(deps.storage, pending_batch)?.total;
+    let stake = bonded.checked_add(pending_total)?;
+    let mut unbonding_loss = Uint128::zero();
+    let mut in_flight_loss = Uint128::zero();
+    if !slashed.is_zero() && !stake.is_zero() {
+        let batches : Vec<(u64, UnbondBatch)> = UNBOND_BATCHES
+            .range(deps.storage, None, None, Order::Ascending)
+            .filter(|item| match item {
+                Ok((batch_id, batch)) => *batch_id == pending_batch
+                    || batch.release_at.is_some_and(|release_at| !release_at.is_expired(&env.block)),
+                Err(_) => true,
+            })
+            .collect::<StdResult<_>>()?;
+        let batch_ids : Vec<u64> = batches.iter().map(|(batch_id, _)| *batch_id).collect();
+        let claims : Vec<((TokenId, u64), Uint128)> = UNBOND_CLAIMS
+            .range(deps.storage, None, None, Order::Ascending)
+            .filter(|item| match item {
+                Ok(((_, batch_id), _)) => batch_ids.contains(batch_id),
+                Err(_) => true,
+            })
+            .collect::<StdResult<_>>()?;
+
+        let mut batch_losses : BTreeMap<u64, Uint128> = BTreeMap::new();
+        for ((nft_id, batch_id), amount) in claims {
+            let loss = amount.multiply_ratio(slashed, stake);
+            UNBOND_CLAIMS.save(deps.storage, (&nft_id, batch_id), &(amount - loss))?;
+            *batch_losses.entry(batch_id).or_default() += loss;
+        }
+        for (batch_id, mut batch) in batches {
+            let loss = batch_losses.get(&batch_id).copied().unwrap_or_default();
+            batch.total -= loss;
+            UNBOND_BATCHES.save(deps.storage, batch_id, &batch)?;
+            if batch_id == pending_batch {
+                unbonding_loss += loss;
+            } else {
+                in_flight_loss += loss;
+            }
+        }
+        let claimed_loss = unbonding_loss + in_flight_loss;
+        CLAIMED.update(deps.storage, |claimed| -> StdResult<_> { Ok(claimed.checked_sub(claimed_loss)?) })?;
+    }
+
+    // The claims round their loss down, so the rest can exceed BONDED by a few tokens when nearly everything is
+    // slashed. BONDED can not go below zero, the excess is left out of the books
+    let bonded_loss = (slashed - unbonding_loss).min(bonded);
+    let bonded = bonded - bonded_loss;
+    BONDED.save(deps.storage, &bonded)?;
+    let total_shares = TOTAL_SHARES.load(deps.storage)?;
+    let exchange_rate = if total_shares.is_zero() { Decimal::one() } else { Decimal::from_ratio(bonded, total_shares) };
+
+    Ok(res
+        .add_attribute("action", "sync_slashing")
+        .add_attribute("slashed", slashed)
+        .add_attribute("bonded_loss", bonded_loss)
+        .add_attribute("unbonding_loss", unbonding_loss)
+        .add_attribute("in_flight_loss", in_flight_loss)
+        .add_attribute("bonded", bonded)
+        .add_attribute("exchange_rate", exchange_rate.to_string()))
+}
+
 // get_bonded returns the total amount of delegations from contract to all validators
 // it ensures they are all the same denom
 fn get_all_bonded(querier: &QuerierWrapper, contract: &Addr) -> Result<Uint128, ContractError> {
@@ -472,8 +1096,7 @@ fn get_all_bonded(querier: &QuerierWrapper, contract: &Addr) -> Result<Uint128,
         return Ok(Uint128::zero());
     }
     let denom = bonds[0].amount.denom.as_str();
-    bonds.iter().fold(Ok(Uint128::zero()), |racc, d| {
-        let acc = racc?;
+    bonds.iter().try_fold(Uint128::zero(), |acc, d| {
         if d.amount.denom.as_str() != denom {
             Err(ContractError::DifferentBondDenom {
                 denom1: denom.into(),
@@ -485,46 +1108,448 @@ fn get_all_bonded(querier: &QuerierWrapper, contract: &Addr) -> Result<Uint128,
     })
 }
 
-// Collect pending rewards from all validators
-fn execute_collect_rewards ( deps: DepsMut, _env: Env, info: MessageInfo) -> Result<Response, ContractError>{
+// Collect pending rewards from all validators and credit them pro-rata to the bonded NFTs
+fn execute_collect_rewards ( deps: DepsMut, env: Env, info: MessageInfo) -> Result<Response, ContractError>{
     let manager = MANAGER.load(deps.storage)?;
     if info.sender != manager {
         return Err(ContractError::Unauthorized {});
     }
-    // Any validator rewards have been previosly and automatically claimed when 'bonded change' occurred on any registered validator
-    let state = State::new();
-    let msgs : StdResult<Vec<DistributionMsg>> = state.validator.idx
-        .bonded
-        .range(deps.storage,None, None, Order::Descending)
-        .filter(|item|
-            item.as_ref().unwrap().1.bonded > 0)
-        .map(|item| 
-            Ok(DistributionMsg::WithdrawDelegatorReward { validator: item.unwrap().0 }))
-        .collect();
+    let mode = REWARD_MODE.load(deps.storage)?;
+    if mode != RewardMode::Payout {
+        return Err(ContractError::WrongRewardMode { mode: mode.to_string() });
+    }
 
-    let msgs = msgs?;
-    let res = Response::new()
-        .add_messages(msgs)
+    let res = withdraw_rewards(deps, env, mode)?
         .add_attribute("action", "withdraw_delegation_rewards");
     Ok(res)
 }
 
-fn execute_transfer_balance (deps: DepsMut, env: Env, info: MessageInfo) -> Result<Response, ContractError>{
+// Withdraw pending rewards from all validators and delegate them again, growing the bonded amount of every NFT
+fn execute_compound(deps: DepsMut, env: Env, info: MessageInfo) -> Result<Response, ContractError> {
     let manager = MANAGER.load(deps.storage)?;
-    if info.sender != manager {
+    if info.sender != manager && !is_agent(deps.storage, &info.sender, AgentAction::Compound)? {
         return Err(ContractError::Unauthorized {});
     }
-    let balance = deps.querier.query_balance(&env.contract.address, deps.querier.query_bonded_denom()?)?;
-
-    if balance.amount == Uint128::zero() {
-        return Err(ContractError::CustomError { val: "Nothing to transfer. Amount for bonded denom is zero".to_string() })
+    let mode = REWARD_MODE.load(deps.storage)?;
+    if mode != RewardMode::Compound {
+        return Err(ContractError::WrongRewardMode { mode: mode.to_string() });
     }
 
-    let address = TREASURY.load(deps.storage)?;
-    let msg = BankMsg::Send { to_address: address.clone(), amount: vec![balance.clone()] };
+    let res = withdraw_rewards(deps, env, mode)?
+        .add_attribute("action", "compound");
+    Ok(res)
+}
 
-    Ok(Response::new()
-    .add_message(CosmosMsg::Bank(msg))
+// Withdraws the rewards of every validator with bonded tokens as a submessage. Each reply measures the balance change
+// caused by its withdrawal and the last one hands the realised rewards out according to mode.
+// Any validator rewards have been previosly and automatically claimed when 'bonded change' occurred on any registered validator.
+// Those are not credited to the NFTs and remain in the contract balance.
+fn withdraw_rewards(deps: DepsMut, env: Env, mode: RewardMode) -> Result<Response, ContractError> {
+    let state = State::new();
+    let validators : Vec<String> = state.validator.idx
+        .bonded
+        .range(deps.storage,None, None, Order::Descending)
+        .filter(|item|
+            item.as_ref().map_or(true, |(_, validator_info)| validator_info.bonded > 0))
+        .map(|item| item.map(|(address, _)| address))
+        .collect::<StdResult<_>>()?;
+
+    if validators.is_empty() {
+        return Ok(Response::new());
+    }
+
+    let can_be_bonded_denom = deps.querier.query_bonded_denom()?;
+    let balance = deps.querier.query_balance(&env.contract.address, can_be_bonded_denom)?;
+
+    let msgs : Vec<SubMsg> = validators
+        .iter()
+        .map(|validator| SubMsg::reply_on_success(
+            DistributionMsg::WithdrawDelegatorReward { validator: validator.clone() },
+            REPLY_WITHDRAW_REWARDS,
+        ))
+        .collect();
+
+    REWARD_WITHDRAWAL.save(deps.storage, &RewardWithdrawal {
+        validators,
+        balance: balance.amount,
+        rewards: Uint128::zero(),
+        mode,
+    })?;
+
+    Ok(Response::new().add_submessages(msgs))
+}
+
+#[cfg_attr(not(feature = "library"), entry_point)]
+pub fn reply(deps: DepsMut, env: Env, msg: Reply) -> Result<Response, ContractError> {
+    match msg.id {
+        REPLY_WITHDRAW_REWARDS => reply_withdraw_rewards(deps, env),
+        id => Err(ContractError::UnknownReplyId { id }),
+    }
+}
+
+// Records the rewards realised by one WithdrawDelegatorReward submessage
+fn reply_withdraw_rewards(deps: DepsMut, env: Env) -> Result<Response, ContractError> {
+    let mut withdrawal = REWARD_WITHDRAWAL.load(deps.storage)?;
+    let can_be_bonded_denom = deps.querier.query_bonded_denom()?;
+    let balance = deps.querier.query_balance(&env.contract.address, &can_be_bonded_denom)?.amount;
+
+    // Submessages are executed in the order they were added
+    let validator = withdrawal.validators.remove(0);
+    let rewards = balance.saturating_sub(withdrawal.balance);
+    withdrawal.balance = balance;
+    withdrawal.rewards += rewards;
+
+    VALIDATOR_REWARDS.update(deps.storage, &validator, |total| -> StdResult<_> {
+        Ok(total.unwrap_or_default().checked_add(rewards)?)
+    })?;
+    TOTAL_REWARDS.update(deps.storage, |total| -> StdResult<_> {
+        Ok(total.checked_add(rewards)?)
+    })?;
+
+    let res = Response::new()
+        .add_event(Event::new("realised_rewards")
+            .add_attribute("validator", validator)
+            .add_attribute("amount", rewards));
+
+    if !withdrawal.validators.is_empty() {
+        REWARD_WITHDRAWAL.save(deps.storage, &withdrawal)?;
+        return Ok(res);
+    }
+
+    // Last withdrawal: all the rewards are in the contract balance
+    REWARD_WITHDRAWAL.remove(deps.storage);
+    let rewards = withdrawal.rewards;
+    let mut res = res.add_attribute("rewards", rewards);
+    let fee = match withdrawal.mode {
+        RewardMode::Payout => credit_rewards(deps.storage, rewards)?,
+        RewardMode::Compound => {
+            // Without shares there is nobody to compound for, everything goes to the treasury
+            let fee = if TOTAL_SHARES.load(deps.storage)?.is_zero() {
+                rewards
+            } else {
+                rewards.multiply_ratio(FEE_BPS.load(deps.storage)?, MAX_FEE_BPS)
+            };
+            let compounded = rewards - fee;
+            if compounded.is_zero() {
+                fee
+            } else {
+                match chosen_validator(deps.as_ref(), None, compounded) {
+                    Ok(validator_address) => {
+                        // No shares are minted, so the compounded tokens are spread over all the NFTs
+                        add_bonded(deps.storage, &validator_address, compounded)?;
+                        res = res
+                            .add_message(StakingMsg::Delegate {
+                                validator: validator_address.clone(),
+                                amount: coin(compounded.u128(), &can_be_bonded_denom),
+                            })
+                            .add_attribute("compounded", compounded)
+                            .add_attribute("validator", validator_address);
+                        fee
+                    }
+                    // Failing would revert every withdrawal of the round, the rewards are paid out instead
+                    Err(err @ (ContractError::ValidatorsFull { .. } | ContractError::NoValidatorAvailable {})) => {
+                        res = res.add_attribute("compound_skipped", err.to_string());
+                        credit_rewards(deps.storage, rewards)?
+                    }
+                    Err(err) => return Err(err),
+                }
+            }
+        }
+    };
+
+    res = res.add_attribute("fee", fee);
+    if !fee.is_zero() {
+        res = res.add_message(BankMsg::Send {
+            to_address: TREASURY.load(deps.storage)?,
+            amount: vec![coin(fee.u128(), can_be_bonded_denom)],
+        });
+    }
+    Ok(res)
+}
+
+pub fn execute_set_reward_mode(deps: DepsMut, _env: Env, info: MessageInfo, mode: RewardMode) -> Result<Response, ContractError> {
+    let manager = MANAGER.load(deps.storage)?;
+    if info.sender != manager {
+        return Err(ContractError::Unauthorized {});
+    }
+    REWARD_MODE.save(deps.storage, &mode)?;
+
+    Ok(Response::new()
+        .add_attribute("action", "set_reward_mode")
+        .add_attribute("mode", mode.to_string()))
+}
+
+pub fn execute_set_unbond_strategy(deps: DepsMut, _env: Env, info: MessageInfo, strategy: UnbondStrategy) -> Result<Response, ContractError> {
+    let manager = MANAGER.load(deps.storage)?;
+    if info.sender != manager {
+        return Err(ContractError::Unauthorized {});
+    }
+    UNBOND_STRATEGY.save(deps.storage, &strategy)?;
+
+    Ok(Response::new()
+        .add_attribute("action", "set_unbond_strategy")
+        .add_attribute("strategy", strategy.to_string()))
+}
+
+pub fn execute_set_self_service(deps: DepsMut, _env: Env, info: MessageInfo, enabled: bool) -> Result<Response, ContractError> {
+    let manager = MANAGER.load(deps.storage)?;
+    if info.sender != manager {
+        return Err(ContractError::Unauthorized {});
+    }
+    SELF_SERVICE.save(deps.storage, &enabled)?;
+
+    Ok(Response::new()
+        .add_attribute("action", "set_self_service")
+        .add_attribute("enabled", enabled.to_string()))
+}
+
+pub fn execute_propose_role(deps: DepsMut, env: Env, info: MessageInfo, role: Role, address: String, permissions: Option<AgentPermissions>, expires: Option<Expiration>) -> Result<Response, ContractError> {
+    let manager = MANAGER.load(deps.storage)?;
+    if info.sender != manager {
+        return Err(ContractError::Unauthorized {});
+    }
+    deps.api.addr_validate(&address)?;
+    if expires.is_some_and(|expires| expires.is_expired(&env.block)) {
+        return Err(ContractError::InvalidExpiration {});
+    }
+    // A new manager or treasury proposal replaces the previous one, agents are proposed side by side
+    if role != Role::Agent {
+        let proposed : Vec<String> = ROLE_PROPOSALS
+            .prefix(&role.to_string())
+            .keys(deps.storage, None, None, Order::Ascending)
+            .collect::<StdResult<_>>()?;
+        for proposed_address in proposed {
+            ROLE_PROPOSALS.remove(deps.storage, (&role.to_string(), &proposed_address));
+        }
+    }
+    ROLE_PROPOSALS.save(deps.storage, (&role.to_string(), &address), &RoleProposal { address: address.clone(), permissions, expires })?;
+
+    Ok(Response::new()
+        .add_attribute("action", "propose_role")
+        .add_attribute("role", role.to_string())
+        .add_attribute("address", address))
+}
+
+pub fn execute_accept_role(deps: DepsMut, env: Env, info: MessageInfo, role: Role) -> Result<Response, ContractError> {
+    let proposal = ROLE_PROPOSALS
+        .may_load(deps.storage, (&role.to_string(), info.sender.as_str()))?
+        .ok_or(ContractError::NoPendingProposal { role: role.to_string(), address: info.sender.to_string() })?;
+    if proposal.expires.is_some_and(|expires| expires.is_expired(&env.block)) {
+        return Err(ContractError::ProposalExpired { role: role.to_string() });
+    }
+    ROLE_PROPOSALS.remove(deps.storage, (&role.to_string(), info.sender.as_str()));
+
+    let mut res = Response::new()
+        .add_attribute("action", "accept_role")
+        .add_attribute("role", role.to_string());
+    match role {
+        // The agent joins the agent set
+        Role::Agent => {
+            AGENTS.save(deps.storage, &info.sender, &proposal.permissions.unwrap_or_else(AgentPermissions::all))?;
+        }
+        Role::Manager | Role::Treasury => {
+            let item = if role == Role::Manager { MANAGER } else { TREASURY };
+            res = res.add_attribute("previous", item.load(deps.storage)?);
+            item.save(deps.storage, &proposal.address)?;
+            // The proposals left were made by the previous manager, the new one has to make them again
+            if role == Role::Manager {
+                ROLE_PROPOSALS.clear(deps.storage);
+            }
+        }
+    }
+    Ok(res.add_attribute("address", proposal.address))
+}
+
+pub fn execute_set_agent_permissions(deps: DepsMut, _env: Env, info: MessageInfo, address: String, permissions: AgentPermissions) -> Result<Response, ContractError> {
+    let manager = MANAGER.load(deps.storage)?;
+    if info.sender != manager {
+        return Err(ContractError::Unauthorized {});
+    }
+    let agent = deps.api.addr_validate(&address)?;
+    if !AGENTS.has(deps.storage, &agent) {
+        return Err(ContractError::NotRegisteredAgent { address });
+    }
+    AGENTS.save(deps.storage, &agent, &permissions)?;
+
+    Ok(Response::new()
+        .add_attribute("action", "set_agent_permissions")
+        .add_attribute("agent", agent)
+        .add_attribute("bond", permissions.bond.to_string())
+        .add_attribute("unbond", permissions.unbond.to_string())
+        .add_attribute("claim", permissions.claim.to_string())
+        .add_attribute("compound", permissions.compound.to_string()))
+}
+
+pub fn execute_remove_agent(deps: DepsMut, _env: Env, info: MessageInfo, address: String) -> Result<Response, ContractError> {
+    let manager = MANAGER.load(deps.storage)?;
+    if info.sender != manager {
+        return Err(ContractError::Unauthorized {});
+    }
+    let agent = deps.api.addr_validate(&address)?;
+    if !AGENTS.has(deps.storage, &agent) {
+        return Err(ContractError::NotRegisteredAgent { address });
+    }
+    AGENTS.remove(deps.storage, &agent);
+
+    Ok(Response::new()
+        .add_attribute("action", "remove_agent")
+        .add_attribute("agent", agent))
+}
+
+fn ensure_not_paused(storage: &dyn Storage) -> Result<(), ContractError> {
+    if PAUSED.load(storage)? {
+        return Err(ContractError::Paused {});
+    }
+    Ok(())
+}
+
+pub fn execute_set_guardian(deps: DepsMut, _env: Env, info: MessageInfo, address: Option<String>) -> Result<Response, ContractError> {
+    let manager = MANAGER.load(deps.storage)?;
+    if info.sender != manager {
+        return Err(ContractError::Unauthorized {});
+    }
+    let guardian = match address {
+        Some(address) => {
+            let guardian = deps.api.addr_validate(&address)?;
+            GUARDIAN.save(deps.storage, &guardian)?;
+            guardian.to_string()
+        }
+        None => {
+            GUARDIAN.remove(deps.storage);
+            "none".to_string()
+        }
+    };
+
+    Ok(Response::new()
+        .add_attribute("action", "set_guardian")
+        .add_attribute("guardian", guardian))
+}
+
+pub fn execute_pause(deps: DepsMut, _env: Env, info: MessageInfo) -> Result<Response, ContractError> {
+    let manager = MANAGER.load(deps.storage)?;
+    let guardian = GUARDIAN.may_load(deps.storage)?;
+    if info.sender != manager && guardian.as_ref() != Some(&info.sender) {
+        return Err(ContractError::Unauthorized {});
+    }
+    PAUSED.save(deps.storage, &true)?;
+
+    Ok(Response::new()
+        .add_attribute("action", "pause")
+        .add_attribute("sender", info.sender))
+}
+
+pub fn execute_unpause(deps: DepsMut, _env: Env, info: MessageInfo) -> Result<Response, ContractError> {
+    let manager = MANAGER.load(deps.storage)?;
+    if info.sender != manager {
+        return Err(ContractError::Unauthorized {});
+    }
+    PAUSED.save(deps.storage, &false)?;
+
+    Ok(Response::new().add_attribute("action", "unpause"))
+}
+
+pub fn execute_set_unbond_epoch(deps: DepsMut, _env: Env, info: MessageInfo, epoch: u64) -> Result<Response, ContractError> {
+    let manager = MANAGER.load(deps.storage)?;
+    if info.sender != manager {
+        return Err(ContractError::Unauthorized {});
+    }
+    UNBOND_EPOCH.save(deps.storage, &epoch)?;
+
+    Ok(Response::new()
+        .add_attribute("action", "set_unbond_epoch")
+        .add_attribute("epoch", epoch.to_string()))
+}
+
+// Takes the treasury fee out of the rewards and credits the rest pro-rata to the bonded NFTs.
+// The split is computed on the rewards realised by a withdrawal only, never on the contract balance,
+// which also holds unbonded tokens waiting to be claimed.
+// Returns what goes to the treasury, the fee and the rounding dust. When nothing is bonded all the rewards go to the treasury.
+fn credit_rewards(storage: &mut dyn Storage, rewards: Uint128) -> StdResult<Uint128> {
+    let total_shares = TOTAL_SHARES.load(storage)?;
+    if total_shares.is_zero() {
+        return Ok(rewards);
+    }
+
+    let fee = rewards.multiply_ratio(FEE_BPS.load(storage)?, MAX_FEE_BPS);
+    let index_increase = Decimal::from_ratio(rewards - fee, total_shares);
+    // The index increase is rounded down, only what the shares can earn is credited and the dust goes to the treasury
+    let nft_rewards = total_shares * index_increase;
+    if !nft_rewards.is_zero() {
+        REWARD_INDEX.update(storage, |index| -> StdResult<_> {
+            Ok(index + index_increase)
+        })?;
+        PENDING_REWARDS.update(storage, |total| -> StdResult<_> {
+            Ok(total.checked_add(nft_rewards)?)
+        })?;
+    }
+    Ok(rewards - nft_rewards)
+}
+
+pub fn execute_set_fee_bps(deps: DepsMut, _env: Env, info: MessageInfo, fee_bps: u64) -> Result<Response, ContractError> {
+    let manager = MANAGER.load(deps.storage)?;
+    if info.sender != manager {
+        return Err(ContractError::Unauthorized {});
+    }
+    if fee_bps > MAX_FEE_BPS {
+        return Err(ContractError::InvalidFeeBps { fee_bps });
+    }
+    FEE_BPS.save(deps.storage, &fee_bps)?;
+
+    Ok(Response::new()
+        .add_attribute("action", "set_fee_bps")
+        .add_attribute("fee_bps", fee_bps.to_string()))
+}
+
+// Sends the rewards credited to an NFT to its current owner
+fn execute_claim_rewards(deps: DepsMut, _env: Env, info: MessageInfo, nft_id: TokenId) -> Result<Response, ContractError> {
+    authorize_nft_action(deps.as_ref(), &info.sender, &nft_id, AgentAction::Claim)?;
+
+    let owner = nft_owner(deps.as_ref(), &nft_id)?;
+    let mut position = POSITIONS.may_load(deps.storage, &nft_id)?
+        .ok_or_else(|| ContractError::NoRewards { nft_id: nft_id.clone() })?;
+    position.settle_rewards(REWARD_INDEX.load(deps.storage)?);
+
+    let amount = position.rewards;
+    if amount.is_zero() {
+        return Err(ContractError::NoRewards { nft_id });
+    }
+    position.rewards = Uint128::zero();
+    POSITIONS.save(deps.storage, &nft_id, &position)?;
+
+    PENDING_REWARDS.update(deps.storage, |total| -> StdResult<_> {
+        Ok(total.checked_sub(amount)?)
+    })?;
+
+    let can_be_bonded_denom = deps.querier.query_bonded_denom()?;
+    Ok(Response::new()
+        .add_message(BankMsg::Send {
+            to_address: owner.to_string(),
+            amount: vec![coin(amount.u128(), can_be_bonded_denom)],
+        })
+        .add_attribute("action", "claim_rewards")
+        .add_attribute("nft_id", nft_id)
+        .add_attribute("owner", owner)
+        .add_attribute("amount", amount))
+}
+
+fn execute_transfer_balance (deps: DepsMut, env: Env, info: MessageInfo) -> Result<Response, ContractError>{
+    let manager = MANAGER.load(deps.storage)?;
+    if info.sender != manager {
+        return Err(ContractError::Unauthorized {});
+    }
+    let mut balance = deps.querier.query_balance(&env.contract.address, deps.querier.query_bonded_denom()?)?;
+    // Only the surplus over what is owed to the NFTs can be transferred
+    let reserved = reserved_balance(deps.storage, &env.block)?;
+    if balance.amount <= reserved {
+        return Err(ContractError::NoSurplusBalance { balance: balance.amount, reserved });
+    }
+    balance.amount -= reserved;
+
+    let address = TREASURY.load(deps.storage)?;
+    let msg = BankMsg::Send { to_address: address.clone(), amount: vec![balance.clone()] };
+
+    Ok(Response::new()
+    .add_message(CosmosMsg::Bank(msg))
     .add_attribute("action", "transfer_balance")
     .add_attribute("dst_addr", address)
     .add_attribute("denom", balance.denom)
@@ -533,12 +1558,29 @@ fn execute_transfer_balance (deps: DepsMut, env: Env, info: MessageInfo) -> Resu
 
 }
 
+// Tokens of the contract balance owed to the NFTs: released claims not sent yet and rewards not claimed yet.
+// The pending batch is still delegated and the batches in flight are still unbonding, none of them is in the balance
+fn reserved_balance(storage: &dyn Storage, block: &BlockInfo) -> StdResult<Uint128> {
+    let pending_batch = PENDING_BATCH.load(storage)?;
+    let mut not_released = Uint128::zero();
+    for item in UNBOND_BATCHES.range(storage, None, None, Order::Ascending) {
+        let (batch_id, batch) = item?;
+        if batch_id == pending_batch || batch.release_at.is_some_and(|release_at| !release_at.is_expired(block)) {
+            not_released += batch.total;
+        }
+    }
+    // Claims are only sent once released, so CLAIMED still holds the whole of the batches not released
+    let released = CLAIMED.load(storage)?.checked_sub(not_released)?;
+    Ok(released.checked_add(PENDING_REWARDS.load(storage)?)?)
+}
+
 #[cfg_attr(not(feature = "library"), entry_point)]
 pub fn query(deps: Deps, env: Env, msg: QueryMsg) -> StdResult<Binary> {
     let state = State::new();
     match msg {
         // Returns #[returns(ClaimsResponse)]
-        QueryMsg::Claims { nft_id } => {to_binary(&CLAIMS.query_claims(deps, &Addr::unchecked(nft_id))?)},
+        QueryMsg::Claims { nft_id } => to_binary(&query_claims(deps, nft_id)?),
+        QueryMsg::ClaimStatus { nft_id } => to_binary(&query_claim_status(deps, env, nft_id)?),
         // [returns(Validator_Info)]
         QueryMsg::ValidatorInfo {address} => to_binary(&state.validator.load(deps.storage,&address)?),
         // [returns(Validator_Deposits)]
@@ -547,19 +1589,211 @@ pub fn query(deps: Deps, env: Env, msg: QueryMsg) -> StdResult<Binary> {
         QueryMsg::ContractBonded {} => to_binary(&BONDED.may_load(deps.storage)?.unwrap_or_default()),
         QueryMsg::ContractClaimed{} => to_binary(&CLAIMED.may_load(deps.storage)?.unwrap_or_default()),
         QueryMsg::BondedOnValidator{address} => to_binary(&query_bonded_on_validator(deps, env, address)?),
-        QueryMsg::Agent{} => to_binary(&AGENT.load(deps.storage)?),
+        QueryMsg::Agent{} => to_binary(&query_agent(deps)?),
+        QueryMsg::ListAgents{start_after, limit} => to_binary(&query_list_agents(deps, start_after, limit)?),
         QueryMsg::Manager{} => to_binary(&MANAGER.load(deps.storage)?),
+        QueryMsg::Treasury{} => to_binary(&TREASURY.load(deps.storage)?),
+        QueryMsg::PendingProposals{} => to_binary(&query_pending_proposals(deps)?),
+        QueryMsg::NftContract{} => to_binary(&NFT_CONTRACT.load(deps.storage)?),
+        QueryMsg::SelfService{} => to_binary(&SELF_SERVICE.load(deps.storage)?),
+        QueryMsg::Guardian{} => to_binary(&GUARDIAN.may_load(deps.storage)?),
+        QueryMsg::Paused{} => to_binary(&PAUSED.load(deps.storage)?),
+        QueryMsg::EscrowedNft{nft_id} => to_binary(&ESCROWED_NFTS.may_load(deps.storage, &nft_id)?),
+        QueryMsg::FeeBps{} => to_binary(&FEE_BPS.load(deps.storage)?),
+        QueryMsg::RewardMode{} => to_binary(&REWARD_MODE.load(deps.storage)?),
+        QueryMsg::UnbondStrategy{} => to_binary(&UNBOND_STRATEGY.load(deps.storage)?),
+        QueryMsg::UnbondEpoch{} => to_binary(&UNBOND_EPOCH.load(deps.storage)?),
+        QueryMsg::UnbondBatch{id} => to_binary(&query_unbond_batch(deps, id)?),
+        QueryMsg::UnbondingEntries{address} => to_binary(&unbonding_entries(deps.storage, &env.block, &address)?),
+        QueryMsg::ValidatorRewards{address} => to_binary(&VALIDATOR_REWARDS.may_load(deps.storage, &address)?.unwrap_or_default()),
+        QueryMsg::TotalRewards{} => to_binary(&TOTAL_REWARDS.load(deps.storage)?),
+        QueryMsg::MaxCommission{} => to_binary(&MAX_COMMISSION.load(deps.storage)?),
+        QueryMsg::ValidatorHealth{} => to_binary(&query_validator_health(deps)?),
+        QueryMsg::Reconciliation {} => to_binary(&query_reconciliation(deps, env)?),
         QueryMsg::RewardsBalance {  } => to_binary(&deps.querier.query_balance(&env.contract.address, deps.querier.query_bonded_denom()?)?),
+        QueryMsg::Position { nft_id } => to_binary(&query_position(deps, nft_id)?),
+        QueryMsg::AllPositions { start_after, limit } => to_binary(&query_all_positions(deps, start_after, limit)?),
     }
 }
 
+// Expired proposals are listed until they are replaced
+pub fn query_pending_proposals(deps: Deps) -> StdResult<PendingProposalsResponse> {
+    let mut proposals = vec![];
+    for role in [Role::Manager, Role::Agent, Role::Treasury] {
+        for item in ROLE_PROPOSALS.prefix(&role.to_string()).range(deps.storage, None, None, Order::Ascending) {
+            let (_, proposal) = item?;
+            proposals.push(RoleProposalResponse { role: role.clone(), address: proposal.address, permissions: proposal.permissions, expires: proposal.expires });
+        }
+    }
+    Ok(PendingProposalsResponse { proposals })
+}
+
+// A claim of a batch not undelegated yet is released Never until the batch is processed
+pub fn query_claims(deps: Deps, nft_id: TokenId) -> StdResult<ClaimsResponse> {
+    let claims = UNBOND_CLAIMS
+        .prefix(&nft_id)
+        .range(deps.storage, None, None, Order::Ascending)
+        .map(|item| {
+            let (batch_id, amount) = item?;
+            let batch = UNBOND_BATCHES.load(deps.storage, batch_id)?;
+            Ok(Claim { amount, release_at: batch.release_at.unwrap_or(Expiration::Never {}) })
+        })
+        .collect::<StdResult<_>>()?;
+
+    Ok(ClaimsResponse { claims })
+}
+
+pub fn query_claim_status(deps: Deps, env: Env, nft_id: TokenId) -> StdResult<ClaimStatusResponse> {
+    let mut status = ClaimStatusResponse { pending: Uint128::zero(), claimable: Uint128::zero(), next_release: None };
+    for claim in query_claims(deps, nft_id)?.claims {
+        status.pending += claim.amount;
+        if claim.release_at.is_expired(&env.block) {
+            status.claimable += claim.amount;
+        } else if !matches!(claim.release_at, Expiration::Never {}) && status.next_release.is_none_or(|next| claim.release_at < next) {
+            status.next_release = Some(claim.release_at);
+        }
+    }
+    Ok(status)
+}
+
+pub fn query_unbond_batch(deps: Deps, id: Option<u64>) -> StdResult<UnbondBatchResponse> {
+    let id = match id {
+        Some(id) => id,
+        None => PENDING_BATCH.load(deps.storage)?,
+    };
+    let batch = UNBOND_BATCHES.load(deps.storage, id)?;
+
+    Ok(UnbondBatchResponse { id, total: batch.total, started_at: batch.started_at, release_at: batch.release_at })
+}
+
+pub fn query_validator_health(deps: Deps) -> StdResult<ValidatorHealthResponse> {
+    let state = State::new();
+    let active_validators = deps.querier.query_all_validators()?;
+    let max_commission = MAX_COMMISSION.load(deps.storage)?;
+
+    let validators = state.validator
+        .keys(deps.storage, None, None, Order::Ascending)
+        .map(|address| {
+            let address = address?;
+            let (status, commission) = validator_status(&active_validators, &address, max_commission);
+            Ok(ValidatorHealth { address, status, commission })
+        })
+        .collect::<StdResult<_>>()?;
+
+    Ok(ValidatorHealthResponse { validators })
+}
+
+pub fn query_position(deps: Deps, nft_id: TokenId) -> StdResult<PositionResponse> {
+    let position = POSITIONS.load(deps.storage, &nft_id)?;
+    let reward_index = REWARD_INDEX.load(deps.storage)?;
+    let bonded = BONDED.load(deps.storage)?;
+    let total_shares = TOTAL_SHARES.load(deps.storage)?;
+    position_response(deps, nft_id, position, reward_index, bonded, total_shares)
+}
+
+pub fn query_reconciliation(deps: Deps, env: Env) -> StdResult<ReconciliationResponse> {
+    let mut validators = vec![];
+    let mut total_delegated = Uint128::zero();
+    let mut total_bonded = Uint128::zero();
+    for item in State::default().validator.range(deps.storage, None, None, Order::Ascending) {
+        let (address, validator_info) = item?;
+        let delegated = deps.querier
+            .query_delegation(&env.contract.address, &address)?
+            .map(|delegation| delegation.amount.amount)
+            .unwrap_or_default();
+        let bonded = Uint128::new(validator_info.bonded);
+        total_delegated += delegated;
+        total_bonded += bonded;
+        validators.push(ValidatorReconciliation {
+            address,
+            delegated,
+            bonded,
+            shortfall: bonded.saturating_sub(delegated),
+            surplus: delegated.saturating_sub(bonded),
+        });
+    }
+
+    let pending_batch = PENDING_BATCH.load(deps.storage)?;
+    let balance = deps.querier.query_balance(&env.contract.address, deps.querier.query_bonded_denom()?)?.amount;
+    let reserved = reserved_balance(deps.storage, &env.block)?;
+    Ok(ReconciliationResponse {
+        validators,
+        total_delegated,
+        total_bonded,
+        contract_bonded: BONDED.load(deps.storage)?,
+        pending_unbond: UNBOND_BATCHES.load(deps.storage, pending_batch)?.total,
+        balance,
+        reserved,
+        spare_balance: balance.saturating_sub(reserved),
+    })
+}
+
+// First agent of the set by address, for the clients of the single agent versions
+pub fn query_agent(deps: Deps) -> StdResult<String> {
+    match AGENTS.keys(deps.storage, None, None, Order::Ascending).next() {
+        Some(agent) => Ok(agent?.to_string()),
+        None => Err(StdError::not_found("agent")),
+    }
+}
+
+pub fn query_list_agents(deps: Deps, start_after: Option<String>, limit: Option<u32>) -> StdResult<ListAgentsResponse> {
+    let limit = limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT) as usize;
+    let start_after = start_after.map(Addr::unchecked);
+    let start = start_after.as_ref().map(Bound::exclusive);
+
+    let agents = AGENTS
+        .range(deps.storage, start, None, Order::Ascending)
+        .take(limit)
+        .map(|item| {
+            let (address, permissions) = item?;
+            Ok(AgentResponse { address: address.to_string(), permissions })
+        })
+        .collect::<StdResult<_>>()?;
+
+    Ok(ListAgentsResponse { agents })
+}
+
+pub fn query_all_positions(deps: Deps, start_after: Option<TokenId>, limit: Option<u32>) -> StdResult<AllPositionsResponse> {
+    let limit = limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT) as usize;
+    let start = start_after.as_deref().map(Bound::exclusive);
+    let reward_index = REWARD_INDEX.load(deps.storage)?;
+    let bonded = BONDED.load(deps.storage)?;
+    let total_shares = TOTAL_SHARES.load(deps.storage)?;
+
+    let positions = POSITIONS
+        .range(deps.storage, start, None, Order::Ascending)
+        .take(limit)
+        .map(|item| {
+            let (nft_id, position) = item?;
+            position_response(deps, nft_id, position, reward_index, bonded, total_shares)
+        })
+        .collect::<StdResult<_>>()?;
+
+    Ok(AllPositionsResponse { positions })
+}
+
+fn position_response(deps: Deps, nft_id: TokenId, position: NftPosition, reward_index: Decimal, bonded: Uint128, total_shares: Uint128) -> StdResult<PositionResponse> {
+    let pending_claims = UNBOND_CLAIMS
+        .prefix(&nft_id)
+        .range(deps.storage, None, None, Order::Ascending)
+        .try_fold(Uint128::zero(), |acc, item| -> StdResult<_> { Ok(acc + item?.1) })?;
+
+    Ok(PositionResponse {
+        nft_id,
+        bonded: shares_to_tokens(position.shares, bonded, total_shares),
+        shares: position.shares,
+        pending_claims,
+        rewards: position.rewards + position.unsettled_rewards(reward_index),
+        bonded_since: position.bonded_since,
+    })
+}
+
 pub fn query_bonded_on_validator(deps: Deps, env: Env,  val_address:String) -> StdResult<Uint128> {
      let bonded = bonded_on_validator(&deps.querier, &env.contract.address, &deps.api.addr_validate(&val_address)?).unwrap();
     Ok(bonded)
 }
 
 // get_bonded returns the total amount of delegations from contract to a certain validator
-// Not in use at the moment.
 fn bonded_on_validator(querier: &QuerierWrapper, delegator: &Addr, validator: &Addr) -> Result<Uint128, ContractError> {
     let option_full_delegation = querier.query_delegation(delegator,validator)?;
     if option_full_delegation.is_none() {
@@ -569,7 +1803,7 @@ fn bonded_on_validator(querier: &QuerierWrapper, delegator: &Addr, validator: &A
     let _denom = full_delegation.amount.denom.as_str();
     let amount = full_delegation.amount.amount;
 
-    Ok(Uint128::from(amount))
+    Ok(amount)
 }
 
 // *****************************************************************************************************************************
@@ -581,22 +1815,21 @@ mod tests {
     use super::*;
 
     use cosmwasm_std::testing::{
-        mock_dependencies, mock_env, mock_info, MockQuerier, MOCK_CONTRACT_ADDR,
+        mock_dependencies, mock_env, mock_info, MockApi, MockQuerier, MockStorage, MOCK_CONTRACT_ADDR,
     };
     use cosmwasm_std::{
-        coins, Coin, CosmosMsg, Decimal, FullDelegation, OverflowError, OverflowOperation,
-        Validator, from_binary, OwnedDeps,
+        coins, Coin, Decimal, FullDelegation, Validator, OwnedDeps, SubMsgResponse, SubMsgResult,
+        ContractResult, SystemError, SystemResult, WasmQuery,
     };
-    use cw_controllers::Claim;
-    use cw_utils::{Duration, DAY, HOUR, WEEK};
+    use cw_utils::{Duration, WEEK};
 
     const MANAGER: &str = "manager";
     const AGENT: &str = "agent";
     const TREASURY: &str = "treasury";
+    const NFT_CONTRACT_ADDR: &str = "nft_contract";
+    const OWNER: &str = "owner";
 
-    const DELEGATOR1: &str = "contract";
-
-    const NFT_ID1 :u128 = 1u128;
+    const NFT_ID1 : &str = "1";
 
     const VALIDATOR1: &str = "validator1";
     const VALIDATOR2: &str = "validator2";
@@ -641,6 +1874,56 @@ mod tests {
         );
     }
 
+    // Answers OwnerOf on the NFT contract with owner for every token
+    fn set_nft_owner(querier: &mut MockQuerier, owner: &str) {
+        let owner = owner.to_string();
+        querier.update_wasm(move |query| match query {
+            WasmQuery::Smart { contract_addr, .. } if contract_addr == NFT_CONTRACT_ADDR => {
+                let res = OwnerOfResponse { owner: owner.clone(), approvals: vec![] };
+                SystemResult::Ok(ContractResult::Ok(to_binary(&res).unwrap()))
+            }
+            _ => SystemResult::Err(SystemError::UnsupportedRequest { kind: "wasm".to_string() }),
+        });
+    }
+
+    // Instantiates the contract and registers every validator known to the querier. OWNER owns every NFT
+    fn setup_contract(deps: &mut OwnedDeps<MockStorage, MockApi, MockQuerier>, validators: &[&str]) {
+        set_nft_owner(&mut deps.querier, OWNER);
+        let msg = InstantiateMsg {
+            agent: AGENT.into(),
+            manager: MANAGER.into(),
+            treasury: TREASURY.into(),
+            nft_contract: NFT_CONTRACT_ADDR.into(),
+        };
+        instantiate(deps.as_mut(), mock_env(), mock_info(MANAGER, &[]), msg).unwrap();
+
+        for validator in validators {
+            let msg = ExecuteMsg::AddValidator { 
+                address: validator.to_string(), 
+                bond_denom: "ustake".to_string(), 
+                unbonding_period: WEEK,
+                weight: None,
+                max_bonded: None,
+            };
+            execute(deps.as_mut(), mock_env(), mock_info(MANAGER, &[]), msg).unwrap();
+        }
+    }
+
+    // Replies to the pending WithdrawDelegatorReward submessages, one per contract balance after each withdrawal
+    fn reply_withdrawals(deps: &mut OwnedDeps<MockStorage, MockApi, MockQuerier>, balances: &[u128]) -> Vec<Response> {
+        balances
+            .iter()
+            .map(|balance| {
+                deps.querier.update_balance(MOCK_CONTRACT_ADDR, coins(*balance, "ustake"));
+                let msg = Reply {
+                    id: REPLY_WITHDRAW_REWARDS,
+                    result: SubMsgResult::Ok(SubMsgResponse { events: vec![], data: None }),
+                };
+                reply(deps.as_mut(), mock_env(), msg).unwrap()
+            })
+            .collect()
+    }
+
     // just a test helper, forgive the panic
     fn later(env: &Env, delta: Duration) -> Env {
         let time_delta = match delta {
@@ -652,11 +1935,8 @@ mod tests {
         res
     }
 
-    fn get_claims(deps: Deps, addr: &str) -> Vec<Claim> {
-        CLAIMS
-            .query_claims(deps, &Addr::unchecked(addr))
-            .unwrap()
-            .claims
+    fn get_claims(deps: Deps, nft_id: &str) -> Vec<Claim> {
+        query_claims(deps, nft_id.to_string()).unwrap().claims
     }
 
      #[test]
@@ -670,6 +1950,7 @@ mod tests {
             agent: AGENT.into(),
             manager: MANAGER.into(),
             treasury: TREASURY.into(),
+            nft_contract: NFT_CONTRACT_ADDR.into(),
         };
 
         instantiate(deps.as_mut(), mock_env(), info.clone(), msg).unwrap();
@@ -677,7 +1958,9 @@ mod tests {
         let msg = ExecuteMsg::AddValidator { 
             address: VALIDATOR2.to_string(), 
             bond_denom: "ustake".to_string(), 
-            unbonding_period: WEEK 
+            unbonding_period: WEEK,
+            weight: None,
+            max_bonded: None,
         };
 
         let err = execute(deps.as_mut(), mock_env(), info, msg.clone()).unwrap_err();
@@ -708,6 +1991,7 @@ mod tests {
             agent: AGENT.into(),
             manager: MANAGER.into(),
             treasury: TREASURY.into(),
+            nft_contract: NFT_CONTRACT_ADDR.into(),
         };
 
         instantiate(deps.as_mut(), mock_env(), info.clone(), msg).unwrap();
@@ -715,7 +1999,9 @@ mod tests {
         let msg = ExecuteMsg::AddValidator { 
             address: VALIDATOR1.to_string(), 
             bond_denom: "ustake".to_string(), 
-            unbonding_period: WEEK 
+            unbonding_period: WEEK,
+            weight: None,
+            max_bonded: None,
         };
 
         execute(deps.as_mut(), mock_env(), info.clone(), msg.clone()).unwrap();
@@ -723,7 +2009,9 @@ mod tests {
         let msg = ExecuteMsg::AddValidator { 
             address: VALIDATOR2.to_string(), 
             bond_denom: "ustake".to_string(), 
-            unbonding_period: WEEK 
+            unbonding_period: WEEK,
+            weight: None,
+            max_bonded: None,
         };
 
         execute(deps.as_mut(), mock_env(), info.clone(), msg).unwrap();
@@ -731,7 +2019,9 @@ mod tests {
         let msg = ExecuteMsg::AddValidator { 
             address: VALIDATOR3.to_string(), 
             bond_denom: "ustake".to_string(), 
-            unbonding_period: WEEK 
+            unbonding_period: WEEK,
+            weight: None,
+            max_bonded: None,
         };
 
         let res = execute(deps.as_mut(), mock_env(), info.clone(), msg).unwrap();
@@ -745,7 +2035,9 @@ mod tests {
                 bond_denom: "ustake".to_string(), 
                 unbonding_period: WEEK, 
                 bonded: 0, 
-                claimed: 0 
+                claimed: 0,
+                weight: 1,
+                max_bonded: None,
             }
         );
     }
@@ -761,6 +2053,7 @@ mod tests {
             agent: AGENT.into(),
             manager: MANAGER.into(),
             treasury: TREASURY.into(),
+            nft_contract: NFT_CONTRACT_ADDR.into(),
         };
 
         instantiate(deps.as_mut(), mock_env(), info.clone(), msg).unwrap();
@@ -768,19 +2061,25 @@ mod tests {
         let msg1 = ExecuteMsg::AddValidator { 
             address: VALIDATOR1.to_string(), 
             bond_denom: "ustake".to_string(), 
-            unbonding_period: WEEK 
+            unbonding_period: WEEK,
+            weight: None,
+            max_bonded: None,
         };
 
         let msg2 = ExecuteMsg::AddValidator { 
             address: VALIDATOR2.to_string(), 
             bond_denom: "ustake".to_string(), 
-            unbonding_period: WEEK 
+            unbonding_period: WEEK,
+            weight: None,
+            max_bonded: None,
         };
 
         let msg3 = ExecuteMsg::AddValidator { 
             address: VALIDATOR3.to_string(), 
             bond_denom: "ustake".to_string(), 
-            unbonding_period: WEEK 
+            unbonding_period: WEEK,
+            weight: None,
+            max_bonded: None,
         };
 
         execute(deps.as_mut(), mock_env(), info.clone(), msg1).unwrap();
@@ -790,21 +2089,1263 @@ mod tests {
         let balance = coins(100, "ustake");
         let info = mock_info(AGENT, &balance);  
 
-        let msg = ExecuteMsg::Bond { nft_id: Uint128::from(NFT_ID1) };
+        let msg = ExecuteMsg::Bond { nft_id: NFT_ID1.to_string() };
 
         let res = execute(deps.as_mut(), mock_env(), info.clone(), msg).unwrap();
         assert_eq!(res.attributes[0], ("action", "bond"));
+        assert_eq!(
+            res.messages[0].msg,
+            CosmosMsg::Staking(StakingMsg::Delegate { validator: VALIDATOR1.to_string(), amount: coin(100, "ustake") })
+        );
+
+        let msg = QueryMsg::ValidatorInfo { address: VALIDATOR1.to_string() };
+        let res = query(deps.as_ref(), mock_env(), msg).unwrap();
+        let res : ValidatorInfo = from_binary(&res).unwrap();
+        assert_eq!(res, 
+            ValidatorInfo{ 
+                bond_denom: "ustake".to_string(), 
+                unbonding_period: WEEK, 
+                bonded: 100, 
+                claimed: 0,
+                weight: 1,
+                max_bonded: None,
+            }
+        );
+
+        // Next bond goes to the validator with the least amount bonded
+        let res = execute(deps.as_mut(), mock_env(), info, ExecuteMsg::Bond { nft_id: NFT_ID1.to_string() }).unwrap();
+        assert_eq!(res.attributes[3], ("validator", VALIDATOR2));
+
+        let position = query_position(deps.as_ref(), NFT_ID1.to_string()).unwrap();
+        assert_eq!(position.bonded, Uint128::new(200));
+        assert_eq!(position.bonded_since, mock_env().block.time);
+        let bonded: Uint128 = from_binary(&query(deps.as_ref(), mock_env(), QueryMsg::ContractBonded {}).unwrap()).unwrap();
+        assert_eq!(bonded, Uint128::new(200));
+    }
+
+    #[test]
+    fn bond_invalid_payment() {
+        let mut deps = mock_dependencies();
+        set_validator(&mut deps.querier);
+        setup_contract(&mut deps, &[VALIDATOR1]);
+        let msg = ExecuteMsg::Bond { nft_id: NFT_ID1.to_string() };
+
+        let err = execute(deps.as_mut(), mock_env(), mock_info(MANAGER, &coins(100, "ustake")), msg.clone()).unwrap_err();
+        assert_eq!(err, ContractError::Unauthorized {});
+
+        let err = execute(deps.as_mut(), mock_env(), mock_info(AGENT, &[]), msg.clone()).unwrap_err();
+        assert_eq!(err, ContractError::NoFunds {});
+
+        let err = execute(deps.as_mut(), mock_env(), mock_info(AGENT, &[coin(100, "ustake"), coin(100, "uatom")]), msg.clone()).unwrap_err();
+        assert_eq!(err, ContractError::MultipleDenoms {});
+
+        let err = execute(deps.as_mut(), mock_env(), mock_info(AGENT, &coins(100, "uatom")), msg).unwrap_err();
+        assert_eq!(err, ContractError::DenominationCanNotBeBonded { denom: "uatom".to_string() });
+    }
+
+    #[test]
+    fn bond_without_validators() {
+        let mut deps = mock_dependencies();
+        set_validator(&mut deps.querier);
+        setup_contract(&mut deps, &[]);
+
+        let msg = ExecuteMsg::Bond { nft_id: NFT_ID1.to_string() };
+        let err = execute(deps.as_mut(), mock_env(), mock_info(AGENT, &coins(100, "ustake")), msg).unwrap_err();
+        assert_eq!(err, ContractError::NoValidatorsRegistered {});
+    }
+
+    #[test]
+    fn unbond_more_than_bonded() {
+        let mut deps = mock_dependencies();
+        set_validators(&mut deps.querier);
+        setup_contract(&mut deps, &[VALIDATOR1, VALIDATOR2, VALIDATOR3]);
+
+        let msg = ExecuteMsg::Bond { nft_id: NFT_ID1.to_string() };
+        execute(deps.as_mut(), mock_env(), mock_info(AGENT, &coins(100, "ustake")), msg).unwrap();
+
+        let msg = ExecuteMsg::Unbond { nft_id: NFT_ID1.to_string(), amount: Uint128::new(101) };
+        let err = execute(deps.as_mut(), mock_env(), mock_info(AGENT, &[]), msg).unwrap_err();
+        assert_eq!(err, ContractError::UnbondExceedsPosition { nft_id: NFT_ID1.to_string(), bonded: Uint128::new(100), amount: Uint128::new(101) });
+
+        // An NFT that never bonded owns nothing
+        let msg = ExecuteMsg::Unbond { nft_id: "2".to_string(), amount: Uint128::new(1) };
+        let err = execute(deps.as_mut(), mock_env(), mock_info(AGENT, &[]), msg).unwrap_err();
+        assert_eq!(err, ContractError::UnbondExceedsPosition { nft_id: "2".to_string(), bonded: Uint128::zero(), amount: Uint128::new(1) });
+    }
+
+    #[test]
+    fn legacy_claims_are_moved_to_unbond_batches() {
+        let mut deps = mock_dependencies();
+        let env = mock_env();
+        let week = Expiration::AtTime(env.block.time.plus_seconds(7 * 24 * 60 * 60));
+        let past = Expiration::AtTime(env.block.time.minus_seconds(60));
+        LEGACY_CLAIMS.save(deps.as_mut().storage, "1", &vec![Claim::new(100, week), Claim::new(50, past)]).unwrap();
+        LEGACY_CLAIMS.save(deps.as_mut().storage, "2", &vec![Claim::new(30, week)]).unwrap();
+
+        assert_eq!(migrate_legacy_claims(deps.as_mut().storage, env.block.time).unwrap(), 2);
+        assert!(LEGACY_CLAIMS.is_empty(deps.as_ref().storage));
+
+        assert_eq!(get_claims(deps.as_ref(), "1"), vec![Claim::new(100, week), Claim::new(50, past)]);
+        assert_eq!(get_claims(deps.as_ref(), "2"), vec![Claim::new(30, week)]);
+        let status = query_claim_status(deps.as_ref(), env.clone(), "1".to_string()).unwrap();
+        assert_eq!(status, ClaimStatusResponse { pending: Uint128::new(150), claimable: Uint128::new(50), next_release: Some(week) });
+
+        assert_eq!(UNBOND_BATCHES.load(deps.as_ref().storage, 0).unwrap().total, Uint128::new(130));
+        assert_eq!(PENDING_BATCH.load(deps.as_ref().storage).unwrap(), 2);
+        assert_eq!(UNBOND_BATCHES.load(deps.as_ref().storage, 2).unwrap(), UnbondBatch::new(env.block.time));
+
+        // Nothing left to migrate
+        assert_eq!(migrate_legacy_claims(deps.as_mut().storage, env.block.time).unwrap(), 0);
+    }
+
+    #[test]
+    fn migrate_checks_the_stored_version() {
+        let mut deps = mock_dependencies();
+        let msg = || MigrateMsg { nft_contract: Some(NFT_CONTRACT_ADDR.to_string()) };
+
+        set_contract_version(deps.as_mut().storage, "crates.io:cw20-base", "0.1.0").unwrap();
+        let err = migrate(deps.as_mut(), mock_env(), msg()).unwrap_err();
+        assert_eq!(err, ContractError::WrongContract { contract: "crates.io:cw20-base".to_string() });
+
+        set_contract_version(deps.as_mut().storage, CONTRACT_NAME, "99.0.0").unwrap();
+        let err = migrate(deps.as_mut(), mock_env(), msg()).unwrap_err();
+        assert_eq!(err, ContractError::CannotDowngrade { from: "99.0.0".to_string(), to: CONTRACT_VERSION.to_string() });
+
+        set_contract_version(deps.as_mut().storage, CONTRACT_NAME, "v1").unwrap();
+        let err = migrate(deps.as_mut(), mock_env(), msg()).unwrap_err();
+        assert_eq!(err, ContractError::InvalidVersion { version: "v1".to_string() });
+
+        // Same version migrates again without changes
+        set_validators(&mut deps.querier);
+        setup_contract(&mut deps, &[VALIDATOR1]);
+        let res = migrate(deps.as_mut(), mock_env(), MigrateMsg { nft_contract: None }).unwrap();
+        assert_eq!(res.attributes[3], ("initialized", "0"));
+        assert_eq!(res.attributes[4], ("validators", "1"));
+        assert_eq!(res.attributes[5], ("legacy_claims", "0"));
+    }
+
+    #[test]
+    fn migrate_rewrites_the_baseline_layout() {
+        let mut deps = mock_dependencies();
+        let env = mock_env();
+        set_contract_version(deps.as_mut().storage, CONTRACT_NAME, "0.1.0").unwrap();
+        for item in [BONDED, CLAIMED, TOTAL_BONDED, TOTAL_CLAIMED] {
+            item.save(deps.as_mut().storage, &Uint128::zero()).unwrap();
+        }
+        NUMBER_VALIDATORS.save(deps.as_mut().storage, &Uint64::new(2)).unwrap();
+        LEGACY_AGENT.save(deps.as_mut().storage, &AGENT.to_string()).unwrap();
+        for address in [VALIDATOR1, VALIDATOR2] {
+            let legacy = LegacyValidatorInfo {
+                bond_denom: "ustake".to_string(),
+                unbonding_period: WEEK,
+                bonded: 0,
+                claimed: 0,
+                weight: None,
+                max_bonded: None,
+            };
+            LEGACY_VALIDATORS.save(deps.as_mut().storage, address, &legacy).unwrap();
+        }
+        let release_at = Expiration::AtTime(env.block.time.plus_seconds(60));
+        LEGACY_CLAIMS.save(deps.as_mut().storage, NFT_ID1, &vec![Claim::new(100, release_at)]).unwrap();
+
+        let err = migrate(deps.as_mut(), env.clone(), MigrateMsg { nft_contract: None }).unwrap_err();
+        assert_eq!(err, ContractError::MissingNftContract {});
+
+        let res = migrate(deps.as_mut(), env.clone(), MigrateMsg { nft_contract: Some(NFT_CONTRACT_ADDR.to_string()) }).unwrap();
+        assert_eq!(res.attributes[1], ("from_version", "0.1.0"));
+        assert_eq!(res.attributes[2], ("to_version", "0.2.0"));
+        assert_eq!(res.attributes[4], ("validators", "2"));
+        assert_eq!(res.attributes[5], ("legacy_claims", "1"));
+        assert_eq!(res.attributes[6], ("agents", "1"));
+        assert_eq!(LEGACY_AGENT.may_load(deps.as_ref().storage).unwrap(), None);
+        assert_eq!(get_contract_version(deps.as_ref().storage).unwrap().version, CONTRACT_VERSION);
+
+        let validator_info = State::default().validator.load(deps.as_ref().storage, VALIDATOR1).unwrap();
+        assert_eq!(validator_info.weight, DEFAULT_VALIDATOR_WEIGHT);
+        assert_eq!(validator_info.max_bonded, None);
+        assert_eq!(NFT_CONTRACT.load(deps.as_ref().storage).unwrap(), NFT_CONTRACT_ADDR);
+        assert_eq!(UNBOND_EPOCH.load(deps.as_ref().storage).unwrap(), DEFAULT_UNBOND_EPOCH);
+        assert_eq!(get_claims(deps.as_ref(), NFT_ID1), vec![Claim::new(100, release_at)]);
+        assert_eq!(query_unbond_batch(deps.as_ref(), None).unwrap().id, 1);
+
+        // The migrated contract bonds as usual
+        set_validators(&mut deps.querier);
+        set_nft_owner(&mut deps.querier, OWNER);
+        let res = execute(deps.as_mut(), env, mock_info(AGENT, &coins(100, "ustake")), ExecuteMsg::Bond { nft_id: NFT_ID1.to_string() });
+        assert!(res.is_ok());
+    }
+
+    #[test]
+    fn roles_are_transferred_in_two_steps() {
+        let mut deps = mock_dependencies();
+        set_validators(&mut deps.querier);
+        setup_contract(&mut deps, &[]);
+        let mut env = mock_env();
+        let expires = Some(Expiration::AtHeight(env.block.height + 10));
+
+        let propose = ExecuteMsg::ProposeManager { address: "new_manager".to_string(), expires };
+        let err = execute(deps.as_mut(), env.clone(), mock_info(AGENT, &[]), propose.clone()).unwrap_err();
+        assert_eq!(err, ContractError::Unauthorized {});
+        let err = execute(deps.as_mut(), env.clone(), mock_info(MANAGER, &[]), ExecuteMsg::ProposeManager {
+            address: "new_manager".to_string(),
+            expires: Some(Expiration::AtHeight(env.block.height)),
+        }).unwrap_err();
+        assert_eq!(err, ContractError::InvalidExpiration {});
+        let err = execute(deps.as_mut(), env.clone(), mock_info("new_manager", &[]), ExecuteMsg::AcceptManager {}).unwrap_err();
+        assert_eq!(err, ContractError::NoPendingProposal { role: "manager".to_string(), address: "new_manager".to_string() });
+
+        execute(deps.as_mut(), env.clone(), mock_info(MANAGER, &[]), propose).unwrap();
+        execute(deps.as_mut(), env.clone(), mock_info(MANAGER, &[]), ExecuteMsg::ProposeTreasury { address: "new_treasury".to_string(), expires: None }).unwrap();
+        let res: PendingProposalsResponse = from_binary(&query(deps.as_ref(), env.clone(), QueryMsg::PendingProposals {}).unwrap()).unwrap();
+        assert_eq!(res.proposals, vec![
+            RoleProposalResponse { role: Role::Manager, address: "new_manager".to_string(), permissions: None, expires },
+            RoleProposalResponse { role: Role::Treasury, address: "new_treasury".to_string(), permissions: None, expires: None },
+        ]);
+
+        // Only the proposed address accepts, and the old manager keeps the role until then
+        let err = execute(deps.as_mut(), env.clone(), mock_info(AGENT, &[]), ExecuteMsg::AcceptManager {}).unwrap_err();
+        assert_eq!(err, ContractError::NoPendingProposal { role: "manager".to_string(), address: AGENT.to_string() });
+        execute(deps.as_mut(), env.clone(), mock_info(MANAGER, &[]), ExecuteMsg::SetSelfService { enabled: true }).unwrap();
+
+        env.block.height += 10;
+        let err = execute(deps.as_mut(), env.clone(), mock_info("new_manager", &[]), ExecuteMsg::AcceptManager {}).unwrap_err();
+        assert_eq!(err, ContractError::ProposalExpired { role: "manager".to_string() });
+
+        // A new manager proposal replaces the previous one
+        execute(deps.as_mut(), env.clone(), mock_info(MANAGER, &[]), ExecuteMsg::ProposeManager { address: "other_manager".to_string(), expires: None }).unwrap();
+        execute(deps.as_mut(), env.clone(), mock_info(MANAGER, &[]), ExecuteMsg::ProposeManager { address: "new_manager".to_string(), expires: None }).unwrap();
+        let err = execute(deps.as_mut(), env.clone(), mock_info("other_manager", &[]), ExecuteMsg::AcceptManager {}).unwrap_err();
+        assert_eq!(err, ContractError::NoPendingProposal { role: "manager".to_string(), address: "other_manager".to_string() });
+        execute(deps.as_mut(), env.clone(), mock_info("new_treasury", &[]), ExecuteMsg::AcceptTreasury {}).unwrap();
+
+        // Proposals of the previous manager can not be accepted once the new one takes over
+        let msg = ExecuteMsg::ProposeAgent { address: "stale_agent".to_string(), permissions: AgentPermissions::all(), expires: None };
+        execute(deps.as_mut(), env.clone(), mock_info(MANAGER, &[]), msg).unwrap();
+        execute(deps.as_mut(), env.clone(), mock_info("new_manager", &[]), ExecuteMsg::AcceptManager {}).unwrap();
+        let err = execute(deps.as_mut(), env.clone(), mock_info("stale_agent", &[]), ExecuteMsg::AcceptAgent {}).unwrap_err();
+        assert_eq!(err, ContractError::NoPendingProposal { role: "agent".to_string(), address: "stale_agent".to_string() });
+        let manager: String = from_binary(&query(deps.as_ref(), env.clone(), QueryMsg::Manager {}).unwrap()).unwrap();
+        assert_eq!(manager, "new_manager");
+        let treasury: String = from_binary(&query(deps.as_ref(), env.clone(), QueryMsg::Treasury {}).unwrap()).unwrap();
+        assert_eq!(treasury, "new_treasury");
+        let res: PendingProposalsResponse = from_binary(&query(deps.as_ref(), env.clone(), QueryMsg::PendingProposals {}).unwrap()).unwrap();
+        assert_eq!(res.proposals, vec![]);
+
+        let err = execute(deps.as_mut(), env.clone(), mock_info(MANAGER, &[]), ExecuteMsg::SetSelfService { enabled: false }).unwrap_err();
+        assert_eq!(err, ContractError::Unauthorized {});
+        execute(deps.as_mut(), env, mock_info("new_manager", &[]), ExecuteMsg::SetSelfService { enabled: false }).unwrap();
+    }
+
+    #[test]
+    fn agents_act_within_their_permissions() {
+        let mut deps = mock_dependencies();
+        set_validators(&mut deps.querier);
+        setup_contract(&mut deps, &[VALIDATOR1]);
+        let bond_only = AgentPermissions { bond: true, unbond: false, claim: false, compound: false };
+
+        // Several agents are proposed at once, each one accepts its own proposal
+        for address in ["agent2", "agent3"] {
+            let msg = ExecuteMsg::ProposeAgent { address: address.to_string(), permissions: bond_only.clone(), expires: None };
+            execute(deps.as_mut(), mock_env(), mock_info(MANAGER, &[]), msg).unwrap();
+        }
+        let res: PendingProposalsResponse = from_binary(&query(deps.as_ref(), mock_env(), QueryMsg::PendingProposals {}).unwrap()).unwrap();
+        assert_eq!(res.proposals.iter().map(|proposal| proposal.address.as_str()).collect::<Vec<_>>(), vec!["agent2", "agent3"]);
+        execute(deps.as_mut(), mock_env(), mock_info("agent2", &[]), ExecuteMsg::AcceptAgent {}).unwrap();
+        execute(deps.as_mut(), mock_env(), mock_info("agent3", &[]), ExecuteMsg::AcceptAgent {}).unwrap();
+        let msg = ExecuteMsg::RemoveAgent { address: "agent3".to_string() };
+        execute(deps.as_mut(), mock_env(), mock_info(MANAGER, &[]), msg).unwrap();
+
+        let msg = QueryMsg::ListAgents { start_after: None, limit: None };
+        let res: ListAgentsResponse = from_binary(&query(deps.as_ref(), mock_env(), msg).unwrap()).unwrap();
+        assert_eq!(res.agents, vec![
+            AgentResponse { address: AGENT.to_string(), permissions: AgentPermissions::all() },
+            AgentResponse { address: "agent2".to_string(), permissions: bond_only },
+        ]);
+        let msg = QueryMsg::ListAgents { start_after: Some(AGENT.to_string()), limit: Some(1) };
+        let res: ListAgentsResponse = from_binary(&query(deps.as_ref(), mock_env(), msg).unwrap()).unwrap();
+        assert_eq!(res.agents.len(), 1);
+        assert_eq!(res.agents[0].address, "agent2");
+        let agent: String = from_binary(&query(deps.as_ref(), mock_env(), QueryMsg::Agent {}).unwrap()).unwrap();
+        assert_eq!(agent, AGENT);
+
+        let bond = ExecuteMsg::Bond { nft_id: NFT_ID1.to_string() };
+        execute(deps.as_mut(), mock_env(), mock_info("agent2", &coins(100, "ustake")), bond).unwrap();
+        let unbond = || ExecuteMsg::Unbond { nft_id: NFT_ID1.to_string(), amount: Uint128::new(50) };
+        let err = execute(deps.as_mut(), mock_env(), mock_info("agent2", &[]), unbond()).unwrap_err();
+        assert_eq!(err, ContractError::Unauthorized {});
+
+        // Only the manager changes the permissions
+        let msg = ExecuteMsg::SetAgentPermissions { address: "agent2".to_string(), permissions: AgentPermissions::all() };
+        let err = execute(deps.as_mut(), mock_env(), mock_info(AGENT, &[]), msg.clone()).unwrap_err();
+        assert_eq!(err, ContractError::Unauthorized {});
+        execute(deps.as_mut(), mock_env(), mock_info(MANAGER, &[]), msg).unwrap();
+        execute(deps.as_mut(), mock_env(), mock_info("agent2", &[]), unbond()).unwrap();
+
+        let msg = ExecuteMsg::RemoveAgent { address: AGENT.to_string() };
+        execute(deps.as_mut(), mock_env(), mock_info(MANAGER, &[]), msg.clone()).unwrap();
+        let err = execute(deps.as_mut(), mock_env(), mock_info(AGENT, &[]), unbond()).unwrap_err();
+        assert_eq!(err, ContractError::Unauthorized {});
+        let err = execute(deps.as_mut(), mock_env(), mock_info(MANAGER, &[]), msg).unwrap_err();
+        assert_eq!(err, ContractError::NotRegisteredAgent { address: AGENT.to_string() });
+    }
+
+    #[test]
+    fn pause_stops_bonds_and_unbonds_but_not_claims() {
+        let mut deps = mock_dependencies();
+        set_validators(&mut deps.querier);
+        setup_contract(&mut deps, &[VALIDATOR1]);
+        execute(deps.as_mut(), mock_env(), mock_info(MANAGER, &[]), ExecuteMsg::SetUnbondEpoch { epoch: 0 }).unwrap();
+        let bond = || ExecuteMsg::Bond { nft_id: NFT_ID1.to_string() };
+        let unbond = || ExecuteMsg::Unbond { nft_id: NFT_ID1.to_string(), amount: Uint128::new(50) };
+        execute(deps.as_mut(), mock_env(), mock_info(AGENT, &coins(100, "ustake")), bond()).unwrap();
+        execute(deps.as_mut(), mock_env(), mock_info(AGENT, &[]), unbond()).unwrap();
+        execute(deps.as_mut(), mock_env(), mock_info(AGENT, &[]), ExecuteMsg::ProcessUnbondBatch {}).unwrap();
+
+        let err = execute(deps.as_mut(), mock_env(), mock_info("guardian", &[]), ExecuteMsg::Pause {}).unwrap_err();
+        assert_eq!(err, ContractError::Unauthorized {});
+        let msg = ExecuteMsg::SetGuardian { address: Some("guardian".to_string()) };
+        execute(deps.as_mut(), mock_env(), mock_info(MANAGER, &[]), msg).unwrap();
+        execute(deps.as_mut(), mock_env(), mock_info("guardian", &[]), ExecuteMsg::Pause {}).unwrap();
+        let paused: bool = from_binary(&query(deps.as_ref(), mock_env(), QueryMsg::Paused {}).unwrap()).unwrap();
+        assert!(paused);
+
+        let err = execute(deps.as_mut(), mock_env(), mock_info(AGENT, &coins(100, "ustake")), bond()).unwrap_err();
+        assert_eq!(err, ContractError::Paused {});
+        let err = execute(deps.as_mut(), mock_env(), mock_info(AGENT, &[]), unbond()).unwrap_err();
+        assert_eq!(err, ContractError::Paused {});
+        let receive = ExecuteMsg::ReceiveNft(Cw721ReceiveMsg {
+            sender: OWNER.to_string(),
+            token_id: "2".to_string(),
+            msg: to_binary(&ReceiveNftMsg::Stake {}).unwrap(),
+        });
+        let err = execute(deps.as_mut(), mock_env(), mock_info(NFT_CONTRACT_ADDR, &[]), receive).unwrap_err();
+        assert_eq!(err, ContractError::Paused {});
+
+        // Claims keep working while paused
+        deps.querier.update_balance(MOCK_CONTRACT_ADDR, coins(50, "ustake"));
+        let msg = ExecuteMsg::Claim { nft_id: NFT_ID1.to_string() };
+        execute(deps.as_mut(), later(&mock_env(), WEEK), mock_info(AGENT, &[]), msg).unwrap();
+
+        // Only the manager unpauses
+        let err = execute(deps.as_mut(), mock_env(), mock_info("guardian", &[]), ExecuteMsg::Unpause {}).unwrap_err();
+        assert_eq!(err, ContractError::Unauthorized {});
+        execute(deps.as_mut(), mock_env(), mock_info(MANAGER, &[]), ExecuteMsg::Unpause {}).unwrap();
+        execute(deps.as_mut(), mock_env(), mock_info(AGENT, &coins(100, "ustake")), bond()).unwrap();
+    }
+
+    #[test]
+    fn slashing_is_shared_by_the_bonded_and_unbonding_nfts() {
+        let mut deps = mock_dependencies();
+        set_validators(&mut deps.querier);
+        setup_contract(&mut deps, &[VALIDATOR1, VALIDATOR2]);
+        for (nft_id, amount) in [("1", 100u128), ("2", 300u128)] {
+            let msg = ExecuteMsg::Bond { nft_id: nft_id.to_string() };
+            execute(deps.as_mut(), mock_env(), mock_info(AGENT, &coins(amount, "ustake")), msg).unwrap();
+        }
+        // 100 of NFT 2 wait in the pending batch, still delegated
+        let msg = ExecuteMsg::Unbond { nft_id: "2".to_string(), amount: Uint128::new(100) };
+        execute(deps.as_mut(), mock_env(), mock_info(AGENT, &[]), msg).unwrap();
+
+        // validator2 loses 20% of its 300
+        deps.querier.update_staking(
+            "ustake",
+            &[sample_validator(VALIDATOR1), sample_validator(VALIDATOR2)],
+            &[sample_delegation(VALIDATOR1, coin(100, "ustake")), sample_delegation(VALIDATOR2, coin(240, "ustake"))],
+        );
+        let err = execute(deps.as_mut(), mock_env(), mock_info(AGENT, &[]), ExecuteMsg::SyncSlashing {}).unwrap_err();
+        assert_eq!(err, ContractError::Unauthorized {});
+        let res = execute(deps.as_mut(), mock_env(), mock_info(MANAGER, &[]), ExecuteMsg::SyncSlashing {}).unwrap();
+        assert_eq!(res.events, vec![Event::new("slash")
+            .add_attribute("validator", VALIDATOR2)
+            .add_attribute("amount", "60")
+            .add_attribute("bonded", "240")]);
+        // 15% of the stake is lost: 45 of the 300 bonded and 15 of the 100 unbonding
+        assert_eq!(res.attributes[1], ("slashed", "60"));
+        assert_eq!(res.attributes[2], ("bonded_loss", "45"));
+        assert_eq!(res.attributes[3], ("unbonding_loss", "15"));
+        assert_eq!(res.attributes[6], ("exchange_rate", "0.85"));
+
+        assert_eq!(State::default().validator.load(&deps.storage, VALIDATOR2).unwrap().bonded, 240);
+        assert_eq!(BONDED.load(&deps.storage).unwrap(), Uint128::new(255));
+        assert_eq!(query_position(deps.as_ref(), "1".to_string()).unwrap().bonded, Uint128::new(85));
+        assert_eq!(query_position(deps.as_ref(), "2".to_string()).unwrap().bonded, Uint128::new(170));
+        assert_eq!(get_claims(deps.as_ref(), "2"), vec![Claim { amount: Uint128::new(85), release_at: Expiration::Never {} }]);
+        assert_eq!(query_unbond_batch(deps.as_ref(), None).unwrap().total, Uint128::new(85));
+        assert_eq!(CLAIMED.load(&deps.storage).unwrap(), Uint128::new(85));
+
+        // Nothing changes once the books match the chain
+        let res = execute(deps.as_mut(), mock_env(), mock_info(MANAGER, &[]), ExecuteMsg::SyncSlashing {}).unwrap();
+        assert!(res.events.is_empty());
+        assert_eq!(res.attributes[1], ("slashed", "0"));
+    }
+
+    #[test]
+    fn slashing_writes_down_the_batches_in_flight() {
+        let mut deps = mock_dependencies();
+        set_validators(&mut deps.querier);
+        setup_contract(&mut deps, &[VALIDATOR1]);
+        execute(deps.as_mut(), mock_env(), mock_info(MANAGER, &[]), ExecuteMsg::SetUnbondEpoch { epoch: 0 }).unwrap();
+        execute(deps.as_mut(), mock_env(), mock_info(AGENT, &coins(400, "ustake")), ExecuteMsg::Bond { nft_id: NFT_ID1.to_string() }).unwrap();
+        let msg = ExecuteMsg::Unbond { nft_id: NFT_ID1.to_string(), amount: Uint128::new(100) };
+        execute(deps.as_mut(), mock_env(), mock_info(AGENT, &[]), msg).unwrap();
+        execute(deps.as_mut(), mock_env(), mock_info(AGENT, &[]), ExecuteMsg::ProcessUnbondBatch {}).unwrap();
+
+        // 20% of the delegation is lost while batch 0 is unbonding, its undelegation is slashed the same
+        deps.querier.update_staking("ustake", &[sample_validator(VALIDATOR1)], &[sample_delegation(VALIDATOR1, coin(240, "ustake"))]);
+        let res = execute(deps.as_mut(), mock_env(), mock_info(MANAGER, &[]), ExecuteMsg::SyncSlashing {}).unwrap();
+        assert_eq!(res.attributes[2], ("bonded_loss", "60"));
+        assert_eq!(res.attributes[4], ("in_flight_loss", "20"));
+
+        assert_eq!(BONDED.load(&deps.storage).unwrap(), Uint128::new(240));
+        assert_eq!(CLAIMED.load(&deps.storage).unwrap(), Uint128::new(80));
+        assert_eq!(query_unbond_batch(deps.as_ref(), Some(0)).unwrap().total, Uint128::new(80));
+
+        // The claim pays what the chain releases
+        deps.querier.update_balance(MOCK_CONTRACT_ADDR, coins(80, "ustake"));
+        let msg = ExecuteMsg::Claim { nft_id: NFT_ID1.to_string() };
+        let res = execute(deps.as_mut(), later(&mock_env(), WEEK), mock_info(AGENT, &[]), msg).unwrap();
+        assert_eq!(res.messages[0].msg, CosmosMsg::Bank(BankMsg::Send { to_address: OWNER.to_string(), amount: coins(80, "ustake") }));
+    }
+
+    #[test]
+    fn reconciliation_reports_every_discrepancy() {
+        let mut deps = mock_dependencies();
+        set_validators(&mut deps.querier);
+        setup_contract(&mut deps, &[VALIDATOR1, VALIDATOR2]);
+        for (nft_id, amount) in [("1", 100u128), ("2", 300u128)] {
+            let msg = ExecuteMsg::Bond { nft_id: nft_id.to_string() };
+            execute(deps.as_mut(), mock_env(), mock_info(AGENT, &coins(amount, "ustake")), msg).unwrap();
+        }
+        let msg = ExecuteMsg::Unbond { nft_id: "1".to_string(), amount: Uint128::new(50) };
+        execute(deps.as_mut(), mock_env(), mock_info(AGENT, &[]), msg).unwrap();
+
+        deps.querier.update_staking(
+            "ustake",
+            &[sample_validator(VALIDATOR1), sample_validator(VALIDATOR2)],
+            &[sample_delegation(VALIDATOR1, coin(100, "ustake")), sample_delegation(VALIDATOR2, coin(240, "ustake"))],
+        );
+        deps.querier.update_balance(MOCK_CONTRACT_ADDR, coins(80, "ustake"));
+
+        let res: ReconciliationResponse = from_binary(&query(deps.as_ref(), mock_env(), QueryMsg::Reconciliation {}).unwrap()).unwrap();
+        assert_eq!(res, ReconciliationResponse {
+            validators: vec![
+                ValidatorReconciliation {
+                    address: VALIDATOR1.to_string(),
+                    delegated: Uint128::new(100),
+                    bonded: Uint128::new(100),
+                    shortfall: Uint128::zero(),
+                    surplus: Uint128::zero(),
+                },
+                ValidatorReconciliation {
+                    address: VALIDATOR2.to_string(),
+                    delegated: Uint128::new(240),
+                    bonded: Uint128::new(300),
+                    shortfall: Uint128::new(60),
+                    surplus: Uint128::zero(),
+                },
+            ],
+            total_delegated: Uint128::new(340),
+            total_bonded: Uint128::new(400),
+            contract_bonded: Uint128::new(350),
+            pending_unbond: Uint128::new(50),
+            balance: Uint128::new(80),
+            reserved: Uint128::zero(),
+            spare_balance: Uint128::new(80),
+        });
+    }
+
+    #[test]
+    fn unbond_updates_every_validator() {
+        let mut deps = mock_dependencies();
+        set_validators(&mut deps.querier);
+        setup_contract(&mut deps, &[VALIDATOR1, VALIDATOR2, VALIDATOR3]);
+
+        for _ in 0..3 {
+            let msg = ExecuteMsg::Bond { nft_id: NFT_ID1.to_string() };
+            execute(deps.as_mut(), mock_env(), mock_info(AGENT, &coins(100, "ustake")), msg).unwrap();
+        }
+
+        let msg = ExecuteMsg::Unbond { nft_id: NFT_ID1.to_string(), amount: Uint128::zero() };
+        let err = execute(deps.as_mut(), mock_env(), mock_info(AGENT, &[]), msg).unwrap_err();
+        assert_eq!(err, ContractError::InvalidZeroAmount {});
 
-        // let msg = QueryMsg::ValidatorInfo { address: VALIDATOR1.to_string() };
-        // let res = query(deps.as_ref(), mock_env(), msg).unwrap();
-        // let res : ValidatorInfo = from_binary(&res).unwrap();
-        // assert_eq!(res, 
-        //     ValidatorInfo{ 
-        //         bond_denom: "ustake".to_string(), 
-        //         unbonding_period: WEEK, 
-        //         bonded: 0, 
-        //         claimed: 0 
-        //     }
-        // );
+        // Unbonds join the pending batch, nothing is undelegated yet
+        for amount in [100u128, 50u128] {
+            let msg = ExecuteMsg::Unbond { nft_id: NFT_ID1.to_string(), amount: Uint128::new(amount) };
+            let res = execute(deps.as_mut(), mock_env(), mock_info(AGENT, &[]), msg).unwrap();
+            assert!(res.messages.is_empty());
+            assert_eq!(res.attributes[3], ("batch_id", "0"));
+        }
+        assert_eq!(get_claims(deps.as_ref(), NFT_ID1), vec![Claim { amount: Uint128::new(150), release_at: Expiration::Never {} }]);
+        assert_eq!(BONDED.load(&deps.storage).unwrap(), Uint128::new(150));
+
+        let process = ExecuteMsg::ProcessUnbondBatch {};
+        let err = execute(deps.as_mut(), mock_env(), mock_info(AGENT, &[]), process.clone()).unwrap_err();
+        let ready_at = mock_env().block.time.plus_seconds(DEFAULT_UNBOND_EPOCH);
+        assert_eq!(err, ContractError::UnbondEpochNotElapsed { ready_at });
+
+        let env = later(&mock_env(), Duration::Time(DEFAULT_UNBOND_EPOCH));
+        let res = execute(deps.as_mut(), env.clone(), mock_info(AGENT, &[]), process.clone()).unwrap();
+        assert_eq!(res.messages.len(), 3);
+
+        let state = State::new();
+        for validator in [VALIDATOR1, VALIDATOR2, VALIDATOR3] {
+            let validator_info = state.validator.load(&deps.storage, validator).unwrap();
+            assert_eq!((validator_info.bonded, validator_info.claimed), (50, 50));
+        }
+        let release_at = WEEK.after(&env.block);
+        assert_eq!(get_claims(deps.as_ref(), NFT_ID1), vec![Claim { amount: Uint128::new(150), release_at }]);
+        assert_eq!(CLAIMED.load(&deps.storage).unwrap(), Uint128::new(150));
+
+        // A new batch collects the next unbonds
+        let batch: UnbondBatchResponse = from_binary(&query(deps.as_ref(), mock_env(), QueryMsg::UnbondBatch { id: None }).unwrap()).unwrap();
+        assert_eq!(batch, UnbondBatchResponse { id: 1, total: Uint128::zero(), started_at: env.block.time, release_at: None });
+        let err = execute(deps.as_mut(), later(&env, Duration::Time(DEFAULT_UNBOND_EPOCH)), mock_info(AGENT, &[]), process).unwrap_err();
+        assert_eq!(err, ContractError::EmptyUnbondBatch {});
+
+        let msg = QueryMsg::ClaimStatus { nft_id: NFT_ID1.to_string() };
+        let status: ClaimStatusResponse = from_binary(&query(deps.as_ref(), env.clone(), msg.clone()).unwrap()).unwrap();
+        assert_eq!(status, ClaimStatusResponse { pending: Uint128::new(150), claimable: Uint128::zero(), next_release: Some(release_at) });
+        let status: ClaimStatusResponse = from_binary(&query(deps.as_ref(), later(&env, WEEK), msg).unwrap()).unwrap();
+        assert_eq!(status, ClaimStatusResponse { pending: Uint128::new(150), claimable: Uint128::new(150), next_release: None });
+
+        deps.querier.update_balance(MOCK_CONTRACT_ADDR, coins(150, "ustake"));
+        let msg = ExecuteMsg::Claim { nft_id: NFT_ID1.to_string() };
+        let err = execute(deps.as_mut(), env.clone(), mock_info(AGENT, &[]), msg.clone()).unwrap_err();
+        assert_eq!(err, ContractError::NothingToClaim {});
+        let res = execute(deps.as_mut(), later(&env, WEEK), mock_info(AGENT, &[]), msg).unwrap();
+        assert_eq!(res.messages[0].msg, CosmosMsg::Bank(BankMsg::Send { to_address: OWNER.to_string(), amount: coins(150, "ustake") }));
+        assert!(get_claims(deps.as_ref(), NFT_ID1).is_empty());
+        assert_eq!(CLAIMED.load(&deps.storage).unwrap(), Uint128::zero());
+    }
+
+    #[test]
+    fn unbond_batches_wait_for_free_unbonding_entries() {
+        let mut deps = mock_dependencies();
+        set_validators(&mut deps.querier);
+        setup_contract(&mut deps, &[VALIDATOR1, VALIDATOR2]);
+
+        let msg = ExecuteMsg::SetUnbondEpoch { epoch: 0 };
+        let err = execute(deps.as_mut(), mock_env(), mock_info(AGENT, &[]), msg.clone()).unwrap_err();
+        assert_eq!(err, ContractError::Unauthorized {});
+        execute(deps.as_mut(), mock_env(), mock_info(MANAGER, &[]), msg).unwrap();
+        let msg = ExecuteMsg::SetUnbondStrategy { strategy: UnbondStrategy::LargestFirst };
+        execute(deps.as_mut(), mock_env(), mock_info(MANAGER, &[]), msg).unwrap();
+        for _ in 0..2 {
+            let msg = ExecuteMsg::Bond { nft_id: NFT_ID1.to_string() };
+            execute(deps.as_mut(), mock_env(), mock_info(AGENT, &coins(100, "ustake")), msg).unwrap();
+        }
+
+        // Undelegations go to the largest validator with a free entry, 7 entries per validator at most
+        let unbond = ExecuteMsg::Unbond { nft_id: NFT_ID1.to_string(), amount: Uint128::new(10) };
+        let process = ExecuteMsg::ProcessUnbondBatch {};
+        for _ in 0..14 {
+            execute(deps.as_mut(), mock_env(), mock_info(AGENT, &[]), unbond.clone()).unwrap();
+            let res = execute(deps.as_mut(), mock_env(), mock_info(AGENT, &[]), process.clone()).unwrap();
+            assert_eq!(res.messages.len(), 1);
+        }
+        for validator in [VALIDATOR1, VALIDATOR2] {
+            let msg = QueryMsg::UnbondingEntries { address: validator.to_string() };
+            let entries: Vec<Expiration> = from_binary(&query(deps.as_ref(), mock_env(), msg).unwrap()).unwrap();
+            assert_eq!(entries.len(), 7);
+        }
+
+        // No free entry: the batch stays pending and keeps collecting unbonds
+        execute(deps.as_mut(), mock_env(), mock_info(AGENT, &[]), unbond).unwrap();
+        let err = execute(deps.as_mut(), mock_env(), mock_info(AGENT, &[]), process.clone()).unwrap_err();
+        assert_eq!(err, ContractError::NoUnbondingEntryAvailable { amount: Uint128::new(10) });
+        let msg = ExecuteMsg::Unbond { nft_id: NFT_ID1.to_string(), amount: Uint128::new(5) };
+        execute(deps.as_mut(), mock_env(), mock_info(AGENT, &[]), msg).unwrap();
+        let batch: UnbondBatchResponse = from_binary(&query(deps.as_ref(), mock_env(), QueryMsg::UnbondBatch { id: None }).unwrap()).unwrap();
+        assert_eq!((batch.id, batch.total), (14, Uint128::new(15)));
+
+        // Once the first undelegations matured, the batch is undelegated
+        let res = execute(deps.as_mut(), later(&mock_env(), WEEK), mock_info(AGENT, &[]), process).unwrap();
+        assert_eq!(res.messages.len(), 1);
+        assert_eq!(res.attributes[2], ("undelegated", "15"));
+        assert_eq!(get_claims(deps.as_ref(), NFT_ID1).len(), 15);
+
+        // The 14 first batches matured, the last one is released a week later
+        let msg = QueryMsg::ClaimStatus { nft_id: NFT_ID1.to_string() };
+        let status: ClaimStatusResponse = from_binary(&query(deps.as_ref(), later(&mock_env(), WEEK), msg).unwrap()).unwrap();
+        assert_eq!(status, ClaimStatusResponse {
+            pending: Uint128::new(155),
+            claimable: Uint128::new(140),
+            next_release: Some(WEEK.after(&later(&mock_env(), WEEK).block)),
+        });
+        let state = State::new();
+        let bonded : u128 = [VALIDATOR1, VALIDATOR2]
+            .iter()
+            .map(|validator| state.validator.load(&deps.storage, validator).unwrap().bonded)
+            .sum();
+        assert_eq!(bonded, 45);
+    }
+
+    #[test]
+    fn owner_self_service() {
+        let mut deps = mock_dependencies();
+        set_validators(&mut deps.querier);
+        setup_contract(&mut deps, &[VALIDATOR1, VALIDATOR2, VALIDATOR3]);
+
+        let bond = ExecuteMsg::Bond { nft_id: NFT_ID1.to_string() };
+        let err = execute(deps.as_mut(), mock_env(), mock_info(OWNER, &coins(100, "ustake")), bond.clone()).unwrap_err();
+        assert_eq!(err, ContractError::Unauthorized {});
+
+        let msg = ExecuteMsg::SetSelfService { enabled: true };
+        let err = execute(deps.as_mut(), mock_env(), mock_info(AGENT, &[]), msg.clone()).unwrap_err();
+        assert_eq!(err, ContractError::Unauthorized {});
+        execute(deps.as_mut(), mock_env(), mock_info(MANAGER, &[]), msg).unwrap();
+        let enabled: bool = from_binary(&query(deps.as_ref(), mock_env(), QueryMsg::SelfService {}).unwrap()).unwrap();
+        assert!(enabled);
+
+        // The owner acts for its NFT, anybody else is rejected. The agent keeps acting for every NFT
+        execute(deps.as_mut(), mock_env(), mock_info(OWNER, &coins(100, "ustake")), bond.clone()).unwrap();
+        let err = execute(deps.as_mut(), mock_env(), mock_info("stranger", &coins(100, "ustake")), bond.clone()).unwrap_err();
+        assert_eq!(err, ContractError::Unauthorized {});
+        execute(deps.as_mut(), mock_env(), mock_info(AGENT, &coins(100, "ustake")), bond).unwrap();
+
+        let msg = ExecuteMsg::Unbond { nft_id: NFT_ID1.to_string(), amount: Uint128::new(200) };
+        let err = execute(deps.as_mut(), mock_env(), mock_info("stranger", &[]), msg.clone()).unwrap_err();
+        assert_eq!(err, ContractError::Unauthorized {});
+        execute(deps.as_mut(), mock_env(), mock_info(OWNER, &[]), msg).unwrap();
+        let env = later(&mock_env(), Duration::Time(DEFAULT_UNBOND_EPOCH));
+        execute(deps.as_mut(), env.clone(), mock_info(AGENT, &[]), ExecuteMsg::ProcessUnbondBatch {}).unwrap();
+
+        deps.querier.update_balance(MOCK_CONTRACT_ADDR, coins(200, "ustake"));
+        let msg = ExecuteMsg::Claim { nft_id: NFT_ID1.to_string() };
+        let err = execute(deps.as_mut(), later(&env, WEEK), mock_info("stranger", &[]), msg.clone()).unwrap_err();
+        assert_eq!(err, ContractError::Unauthorized {});
+        let res = execute(deps.as_mut(), later(&env, WEEK), mock_info(OWNER, &[]), msg).unwrap();
+        assert_eq!(res.messages[0].msg, CosmosMsg::Bank(BankMsg::Send { to_address: OWNER.to_string(), amount: coins(200, "ustake") }));
+
+        // Back to custodial only
+        let msg = ExecuteMsg::SetSelfService { enabled: false };
+        execute(deps.as_mut(), mock_env(), mock_info(MANAGER, &[]), msg).unwrap();
+        let bond = ExecuteMsg::Bond { nft_id: NFT_ID1.to_string() };
+        let err = execute(deps.as_mut(), mock_env(), mock_info(OWNER, &coins(100, "ustake")), bond).unwrap_err();
+        assert_eq!(err, ContractError::Unauthorized {});
+    }
+
+    #[test]
+    fn receive_nft_checks_sender_and_token_id() {
+        let mut deps = mock_dependencies();
+        set_validators(&mut deps.querier);
+        setup_contract(&mut deps, &[VALIDATOR1]);
+
+        let receive = |token_id: &str| ExecuteMsg::ReceiveNft(Cw721ReceiveMsg {
+            sender: OWNER.to_string(),
+            token_id: token_id.to_string(),
+            msg: to_binary(&ReceiveNftMsg::Stake {}).unwrap(),
+        });
+        let err = execute(deps.as_mut(), mock_env(), mock_info("other_collection", &[]), receive("1")).unwrap_err();
+        assert_eq!(err, ContractError::Unauthorized {});
+        execute(deps.as_mut(), mock_env(), mock_info(NFT_CONTRACT_ADDR, &[]), receive("1")).unwrap();
+
+        // The depositor owns the escrowed NFT without self service, and gets it back when there is nothing to claim
+        set_nft_owner(&mut deps.querier, MOCK_CONTRACT_ADDR);
+        let msg = ExecuteMsg::Claim { nft_id: NFT_ID1.to_string() };
+        let res = execute(deps.as_mut(), mock_env(), mock_info(OWNER, &[]), msg.clone()).unwrap();
+        assert_eq!(res.messages[0].msg, CosmosMsg::Wasm(WasmMsg::Execute {
+            contract_addr: NFT_CONTRACT_ADDR.to_string(),
+            msg: to_binary(&Cw721ExecuteMsg::TransferNft { recipient: OWNER.to_string(), token_id: "1".to_string() }).unwrap(),
+            funds: vec![],
+        }));
+        let err = execute(deps.as_mut(), mock_env(), mock_info(OWNER, &[]), msg).unwrap_err();
+        assert_eq!(err, ContractError::Unauthorized {});
+    }
+
+    #[test]
+    fn escrowed_nft_is_released_after_a_slashed_full_unbond() {
+        let mut deps = mock_dependencies();
+        set_validators(&mut deps.querier);
+        setup_contract(&mut deps, &[VALIDATOR1]);
+        execute(deps.as_mut(), mock_env(), mock_info(MANAGER, &[]), ExecuteMsg::SetUnbondEpoch { epoch: 0 }).unwrap();
+
+        let receive = ExecuteMsg::ReceiveNft(Cw721ReceiveMsg {
+            sender: OWNER.to_string(),
+            token_id: NFT_ID1.to_string(),
+            msg: to_binary(&ReceiveNftMsg::Stake {}).unwrap(),
+        });
+        execute(deps.as_mut(), mock_env(), mock_info(NFT_CONTRACT_ADDR, &[]), receive).unwrap();
+        execute(deps.as_mut(), mock_env(), mock_info(OWNER, &coins(3, "ustake")), ExecuteMsg::Bond { nft_id: NFT_ID1.to_string() }).unwrap();
+        execute(deps.as_mut(), mock_env(), mock_info(AGENT, &coins(7, "ustake")), ExecuteMsg::Bond { nft_id: "2".to_string() }).unwrap();
+
+        // Half of the stake is slashed, the 3 shares of the escrowed NFT are worth 1 token
+        deps.querier.update_staking("ustake", &[sample_validator(VALIDATOR1)], &[sample_delegation(VALIDATOR1, coin(5, "ustake"))]);
+        execute(deps.as_mut(), mock_env(), mock_info(MANAGER, &[]), ExecuteMsg::SyncSlashing {}).unwrap();
+        assert_eq!(query_position(deps.as_ref(), NFT_ID1.to_string()).unwrap().bonded, Uint128::new(1));
+
+        let msg = ExecuteMsg::Unbond { nft_id: NFT_ID1.to_string(), amount: Uint128::new(1) };
+        execute(deps.as_mut(), mock_env(), mock_info(OWNER, &[]), msg).unwrap();
+        assert_eq!(query_position(deps.as_ref(), NFT_ID1.to_string()).unwrap().shares, Uint128::zero());
+        execute(deps.as_mut(), mock_env(), mock_info(AGENT, &[]), ExecuteMsg::ProcessUnbondBatch {}).unwrap();
+
+        deps.querier.update_balance(MOCK_CONTRACT_ADDR, coins(1, "ustake"));
+        let msg = ExecuteMsg::Claim { nft_id: NFT_ID1.to_string() };
+        let res = execute(deps.as_mut(), later(&mock_env(), WEEK), mock_info(OWNER, &[]), msg).unwrap();
+        assert_eq!(res.messages[1].msg, CosmosMsg::Wasm(WasmMsg::Execute {
+            contract_addr: NFT_CONTRACT_ADDR.to_string(),
+            msg: to_binary(&Cw721ExecuteMsg::TransferNft { recipient: OWNER.to_string(), token_id: NFT_ID1.to_string() }).unwrap(),
+            funds: vec![],
+        }));
+    }
+
+    #[test]
+    fn query_positions() {
+        let mut deps = mock_dependencies();
+        set_validators(&mut deps.querier);
+        setup_contract(&mut deps, &[VALIDATOR1, VALIDATOR2, VALIDATOR3]);
+
+        for nft_id in 1..=12u128 {
+            let msg = ExecuteMsg::Bond { nft_id: nft_id.to_string() };
+            execute(deps.as_mut(), later(&mock_env(), Duration::Time(nft_id as u64)), mock_info(AGENT, &coins(100 * nft_id, "ustake")), msg).unwrap();
+        }
+
+        let msg = QueryMsg::Position { nft_id: "3".to_string() };
+        let res: PositionResponse = from_binary(&query(deps.as_ref(), mock_env(), msg).unwrap()).unwrap();
+        assert_eq!(res, PositionResponse {
+            nft_id: "3".to_string(),
+            bonded: Uint128::new(300),
+            shares: Uint128::new(300),
+            pending_claims: Uint128::zero(),
+            rewards: Uint128::zero(),
+            bonded_since: mock_env().block.time.plus_seconds(3),
+        });
+
+        // Unknown NFT
+        query(deps.as_ref(), mock_env(), QueryMsg::Position { nft_id: "13".to_string() }).unwrap_err();
+
+        // Default page size
+        let msg = QueryMsg::AllPositions { start_after: None, limit: None };
+        let res: AllPositionsResponse = from_binary(&query(deps.as_ref(), mock_env(), msg).unwrap()).unwrap();
+        assert_eq!(res.positions.len(), DEFAULT_LIMIT as usize);
+
+        // Walk every page
+        let mut start_after = None;
+        let mut nft_ids = vec![];
+        loop {
+            let msg = QueryMsg::AllPositions { start_after, limit: Some(5) };
+            let res: AllPositionsResponse = from_binary(&query(deps.as_ref(), mock_env(), msg).unwrap()).unwrap();
+            match res.positions.last() {
+                Some(position) => start_after = Some(position.nft_id.clone()),
+                None => break,
+            }
+            nft_ids.extend(res.positions.into_iter().map(|position| position.nft_id));
+        }
+        nft_ids.sort_by_key(|nft_id| nft_id.parse::<u128>().unwrap());
+        assert_eq!(nft_ids, (1..=12u128).map(|nft_id| nft_id.to_string()).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn collect_and_claim_rewards() {
+        let mut deps = mock_dependencies();
+        set_validators(&mut deps.querier);
+        setup_contract(&mut deps, &[VALIDATOR1, VALIDATOR2, VALIDATOR3]);
+
+        execute(deps.as_mut(), mock_env(), mock_info(AGENT, &coins(100, "ustake")), ExecuteMsg::Bond { nft_id: "1".to_string() }).unwrap();
+        execute(deps.as_mut(), mock_env(), mock_info(AGENT, &coins(300, "ustake")), ExecuteMsg::Bond { nft_id: "2".to_string() }).unwrap();
+
+        let err = execute(deps.as_mut(), mock_env(), mock_info(AGENT, &[]), ExecuteMsg::CollectAngelRewards {}).unwrap_err();
+        assert_eq!(err, ContractError::Unauthorized {});
+
+        let res = execute(deps.as_mut(), mock_env(), mock_info(MANAGER, &[]), ExecuteMsg::CollectAngelRewards {}).unwrap();
+        assert_eq!(
+            res.messages,
+            vec![
+                SubMsg::reply_on_success(DistributionMsg::WithdrawDelegatorReward { validator: VALIDATOR2.to_string() }, REPLY_WITHDRAW_REWARDS),
+                SubMsg::reply_on_success(DistributionMsg::WithdrawDelegatorReward { validator: VALIDATOR1.to_string() }, REPLY_WITHDRAW_REWARDS),
+            ]
+        );
+
+        // 15 ustake realised from validator2 and 25 from validator1
+        let res = reply_withdrawals(&mut deps, &[15, 40]);
+        assert_eq!(res[0].events[0], Event::new("realised_rewards").add_attribute("validator", VALIDATOR2).add_attribute("amount", "15"));
+        assert_eq!(res[1].events[0], Event::new("realised_rewards").add_attribute("validator", VALIDATOR1).add_attribute("amount", "25"));
+        assert_eq!(res[1].attributes[0], ("rewards", "40"));
+        assert!(res[1].messages.is_empty());
+
+        let rewards: Uint128 = from_binary(&query(deps.as_ref(), mock_env(), QueryMsg::ValidatorRewards { address: VALIDATOR1.to_string() }).unwrap()).unwrap();
+        assert_eq!(rewards, Uint128::new(25));
+        let rewards: Uint128 = from_binary(&query(deps.as_ref(), mock_env(), QueryMsg::TotalRewards {}).unwrap()).unwrap();
+        assert_eq!(rewards, Uint128::new(40));
+
+        // Rewards are split pro-rata to the bonded amount
+        let position = query_position(deps.as_ref(), "1".to_string()).unwrap();
+        assert_eq!(position.rewards, Uint128::new(10));
+        let position = query_position(deps.as_ref(), "2".to_string()).unwrap();
+        assert_eq!(position.rewards, Uint128::new(30));
+
+        // Bonding again keeps the rewards earned so far
+        execute(deps.as_mut(), mock_env(), mock_info(AGENT, &coins(100, "ustake")), ExecuteMsg::Bond { nft_id: "1".to_string() }).unwrap();
+        let position = query_position(deps.as_ref(), "1".to_string()).unwrap();
+        assert_eq!(position.rewards, Uint128::new(10));
+
+        let msg = ExecuteMsg::ClaimRewards { nft_id: "1".to_string() };
+        let res = execute(deps.as_mut(), mock_env(), mock_info(AGENT, &[]), msg.clone()).unwrap();
+        assert_eq!(
+            res.messages[0].msg,
+            CosmosMsg::Bank(BankMsg::Send { to_address: OWNER.to_string(), amount: coins(10, "ustake") })
+        );
+        let err = execute(deps.as_mut(), mock_env(), mock_info(AGENT, &[]), msg).unwrap_err();
+        assert_eq!(err, ContractError::NoRewards { nft_id: "1".to_string() });
+
+        // Rewards of NFT 2 are not swept into the treasury
+        deps.querier.update_balance(MOCK_CONTRACT_ADDR, coins(50, "ustake"));
+        let res = execute(deps.as_mut(), mock_env(), mock_info(MANAGER, &[]), ExecuteMsg::TransferBalanceToTreasury {}).unwrap();
+        assert_eq!(
+            res.messages[0].msg,
+            CosmosMsg::Bank(BankMsg::Send { to_address: TREASURY.to_string(), amount: coins(20, "ustake") })
+        );
+    }
+
+    #[test]
+    fn collect_rewards_sends_the_rounding_dust_to_the_treasury() {
+        let mut deps = mock_dependencies();
+        set_validator(&mut deps.querier);
+        setup_contract(&mut deps, &[VALIDATOR1]);
+        execute(deps.as_mut(), mock_env(), mock_info(AGENT, &coins(300, "ustake")), ExecuteMsg::Bond { nft_id: "1".to_string() }).unwrap();
+
+        // 100 ustake over 300 shares only credits 99 of them
+        execute(deps.as_mut(), mock_env(), mock_info(MANAGER, &[]), ExecuteMsg::CollectAngelRewards {}).unwrap();
+        let res = reply_withdrawals(&mut deps, &[100]);
+        assert_eq!(
+            res[0].messages[0].msg,
+            CosmosMsg::Bank(BankMsg::Send { to_address: TREASURY.to_string(), amount: coins(1, "ustake") })
+        );
+        assert_eq!(PENDING_REWARDS.load(deps.as_ref().storage).unwrap(), Uint128::new(99));
+        assert_eq!(query_position(deps.as_ref(), "1".to_string()).unwrap().rewards, Uint128::new(99));
+    }
+
+    #[test]
+    fn collect_rewards_with_fee() {
+        let mut deps = mock_dependencies();
+        set_validators(&mut deps.querier);
+        setup_contract(&mut deps, &[VALIDATOR1, VALIDATOR2, VALIDATOR3]);
+
+        let msg = ExecuteMsg::SetFeeBps { fee_bps: 1_000 };
+        let err = execute(deps.as_mut(), mock_env(), mock_info(AGENT, &[]), msg).unwrap_err();
+        assert_eq!(err, ContractError::Unauthorized {});
+        let msg = ExecuteMsg::SetFeeBps { fee_bps: 10_001 };
+        let err = execute(deps.as_mut(), mock_env(), mock_info(MANAGER, &[]), msg).unwrap_err();
+        assert_eq!(err, ContractError::InvalidFeeBps { fee_bps: 10_001 });
+        let msg = ExecuteMsg::SetFeeBps { fee_bps: 1_000 };
+        execute(deps.as_mut(), mock_env(), mock_info(MANAGER, &[]), msg).unwrap();
+        let fee_bps: u64 = from_binary(&query(deps.as_ref(), mock_env(), QueryMsg::FeeBps {}).unwrap()).unwrap();
+        assert_eq!(fee_bps, 1_000);
+
+        execute(deps.as_mut(), mock_env(), mock_info(AGENT, &coins(100, "ustake")), ExecuteMsg::Bond { nft_id: "1".to_string() }).unwrap();
+        execute(deps.as_mut(), mock_env(), mock_info(AGENT, &coins(300, "ustake")), ExecuteMsg::Bond { nft_id: "2".to_string() }).unwrap();
+
+        // Unbonded tokens waiting in the contract are not part of the split
+        deps.querier.update_balance(MOCK_CONTRACT_ADDR, coins(1_000, "ustake"));
+
+        execute(deps.as_mut(), mock_env(), mock_info(MANAGER, &[]), ExecuteMsg::CollectAngelRewards {}).unwrap();
+        let res = reply_withdrawals(&mut deps, &[1_000, 1_040]);
+        assert_eq!(res[1].attributes[1], ("fee", "4"));
+        assert_eq!(
+            res[1].messages[0].msg,
+            CosmosMsg::Bank(BankMsg::Send { to_address: TREASURY.to_string(), amount: coins(4, "ustake") })
+        );
+        assert_eq!(query_position(deps.as_ref(), "1".to_string()).unwrap().rewards, Uint128::new(9));
+        assert_eq!(query_position(deps.as_ref(), "2".to_string()).unwrap().rewards, Uint128::new(27));
+    }
+
+    #[test]
+    fn transfer_balance_keeps_claims() {
+        let mut deps = mock_dependencies();
+        set_validator(&mut deps.querier);
+        setup_contract(&mut deps, &[VALIDATOR1]);
+
+        // Unbonded tokens owed to the NFTs
+        CLAIMED.save(deps.as_mut().storage, &Uint128::new(60)).unwrap();
+        deps.querier.update_balance(MOCK_CONTRACT_ADDR, coins(60, "ustake"));
+        let err = execute(deps.as_mut(), mock_env(), mock_info(MANAGER, &[]), ExecuteMsg::TransferBalanceToTreasury {}).unwrap_err();
+        assert_eq!(err, ContractError::NoSurplusBalance { balance: Uint128::new(60), reserved: Uint128::new(60) });
+
+        deps.querier.update_balance(MOCK_CONTRACT_ADDR, coins(100, "ustake"));
+        let res = execute(deps.as_mut(), mock_env(), mock_info(MANAGER, &[]), ExecuteMsg::TransferBalanceToTreasury {}).unwrap();
+        assert_eq!(
+            res.messages[0].msg,
+            CosmosMsg::Bank(BankMsg::Send { to_address: TREASURY.to_string(), amount: coins(40, "ustake") })
+        );
+    }
+
+    #[test]
+    fn transfer_balance_only_keeps_released_claims() {
+        let mut deps = mock_dependencies();
+        set_validator(&mut deps.querier);
+        setup_contract(&mut deps, &[VALIDATOR1]);
+        execute(deps.as_mut(), mock_env(), mock_info(MANAGER, &[]), ExecuteMsg::SetUnbondEpoch { epoch: 0 }).unwrap();
+        execute(deps.as_mut(), mock_env(), mock_info(AGENT, &coins(100, "ustake")), ExecuteMsg::Bond { nft_id: NFT_ID1.to_string() }).unwrap();
+
+        // One batch unbonding and one still delegated, neither is in the balance
+        let msg = ExecuteMsg::Unbond { nft_id: NFT_ID1.to_string(), amount: Uint128::new(30) };
+        execute(deps.as_mut(), mock_env(), mock_info(AGENT, &[]), msg).unwrap();
+        execute(deps.as_mut(), mock_env(), mock_info(AGENT, &[]), ExecuteMsg::ProcessUnbondBatch {}).unwrap();
+        let msg = ExecuteMsg::Unbond { nft_id: NFT_ID1.to_string(), amount: Uint128::new(20) };
+        execute(deps.as_mut(), mock_env(), mock_info(AGENT, &[]), msg).unwrap();
+
+        deps.querier.update_balance(MOCK_CONTRACT_ADDR, coins(10, "ustake"));
+        let res = execute(deps.as_mut(), mock_env(), mock_info(MANAGER, &[]), ExecuteMsg::TransferBalanceToTreasury {}).unwrap();
+        assert_eq!(
+            res.messages[0].msg,
+            CosmosMsg::Bank(BankMsg::Send { to_address: TREASURY.to_string(), amount: coins(10, "ustake") })
+        );
+
+        // Once released, the first batch is owed to the NFT
+        deps.querier.update_balance(MOCK_CONTRACT_ADDR, coins(30, "ustake"));
+        let env = later(&mock_env(), WEEK);
+        let err = execute(deps.as_mut(), env.clone(), mock_info(MANAGER, &[]), ExecuteMsg::TransferBalanceToTreasury {}).unwrap_err();
+        assert_eq!(err, ContractError::NoSurplusBalance { balance: Uint128::new(30), reserved: Uint128::new(30) });
+        deps.querier.update_balance(MOCK_CONTRACT_ADDR, coins(40, "ustake"));
+        let res: ReconciliationResponse = from_binary(&query(deps.as_ref(), env, QueryMsg::Reconciliation {}).unwrap()).unwrap();
+        assert_eq!((res.reserved, res.spare_balance), (Uint128::new(30), Uint128::new(10)));
+    }
+
+    #[test]
+    fn compound_rewards() {
+        let mut deps = mock_dependencies();
+        set_validators(&mut deps.querier);
+        setup_contract(&mut deps, &[VALIDATOR1, VALIDATOR2, VALIDATOR3]);
+        execute(deps.as_mut(), mock_env(), mock_info(MANAGER, &[]), ExecuteMsg::SetFeeBps { fee_bps: 1_000 }).unwrap();
+
+        execute(deps.as_mut(), mock_env(), mock_info(AGENT, &coins(100, "ustake")), ExecuteMsg::Bond { nft_id: "1".to_string() }).unwrap();
+        execute(deps.as_mut(), mock_env(), mock_info(AGENT, &coins(300, "ustake")), ExecuteMsg::Bond { nft_id: "2".to_string() }).unwrap();
+
+        // Rewards are paid out by default
+        let err = execute(deps.as_mut(), mock_env(), mock_info(AGENT, &[]), ExecuteMsg::Compound {}).unwrap_err();
+        assert_eq!(err, ContractError::WrongRewardMode { mode: "payout".to_string() });
+
+        let msg = ExecuteMsg::SetRewardMode { mode: RewardMode::Compound };
+        let err = execute(deps.as_mut(), mock_env(), mock_info(AGENT, &[]), msg.clone()).unwrap_err();
+        assert_eq!(err, ContractError::Unauthorized {});
+        execute(deps.as_mut(), mock_env(), mock_info(MANAGER, &[]), msg).unwrap();
+        let err = execute(deps.as_mut(), mock_env(), mock_info(MANAGER, &[]), ExecuteMsg::CollectAngelRewards {}).unwrap_err();
+        assert_eq!(err, ContractError::WrongRewardMode { mode: "compound".to_string() });
+
+        let err = execute(deps.as_mut(), mock_env(), mock_info(TREASURY, &[]), ExecuteMsg::Compound {}).unwrap_err();
+        assert_eq!(err, ContractError::Unauthorized {});
+        let res = execute(deps.as_mut(), mock_env(), mock_info(AGENT, &[]), ExecuteMsg::Compound {}).unwrap();
+        assert_eq!(res.messages.len(), 2);
+        let res = reply_withdrawals(&mut deps, &[0, 40]);
+        assert!(res[0].messages.is_empty());
+        assert_eq!(
+            res[1].messages.iter().map(|msg| msg.msg.clone()).collect::<Vec<_>>(),
+            vec![
+                CosmosMsg::Staking(StakingMsg::Delegate { validator: VALIDATOR3.to_string(), amount: coin(36, "ustake") }),
+                CosmosMsg::Bank(BankMsg::Send { to_address: TREASURY.to_string(), amount: coins(4, "ustake") }),
+            ]
+        );
+
+        // Compounded rewards grow every position pro-rata
+        assert_eq!(query_position(deps.as_ref(), "1".to_string()).unwrap().bonded, Uint128::new(109));
+        assert_eq!(query_position(deps.as_ref(), "2".to_string()).unwrap().bonded, Uint128::new(327));
+        assert_eq!(query_position(deps.as_ref(), "1".to_string()).unwrap().rewards, Uint128::zero());
+        let bonded: Uint128 = from_binary(&query(deps.as_ref(), mock_env(), QueryMsg::ContractBonded {}).unwrap()).unwrap();
+        assert_eq!(bonded, Uint128::new(436));
+
+        // New bonds do not share the rewards compounded before them
+        execute(deps.as_mut(), mock_env(), mock_info(AGENT, &coins(436, "ustake")), ExecuteMsg::Bond { nft_id: "3".to_string() }).unwrap();
+        let position = query_position(deps.as_ref(), "3".to_string()).unwrap();
+        assert_eq!((position.bonded, position.shares), (Uint128::new(436), Uint128::new(400)));
+        assert_eq!(query_position(deps.as_ref(), "1".to_string()).unwrap().bonded, Uint128::new(109));
+    }
+
+    #[test]
+    fn compound_pays_out_when_no_validator_has_room() {
+        let mut deps = mock_dependencies();
+        set_validator(&mut deps.querier);
+        setup_contract(&mut deps, &[VALIDATOR1]);
+        execute(deps.as_mut(), mock_env(), mock_info(MANAGER, &[]), ExecuteMsg::SetRewardMode { mode: RewardMode::Compound }).unwrap();
+        execute(deps.as_mut(), mock_env(), mock_info(AGENT, &coins(100, "ustake")), ExecuteMsg::Bond { nft_id: "1".to_string() }).unwrap();
+        let msg = ExecuteMsg::SetValidatorMaxBonded { address: VALIDATOR1.to_string(), max_bonded: Some(Uint128::new(100)) };
+        execute(deps.as_mut(), mock_env(), mock_info(MANAGER, &[]), msg).unwrap();
+
+        execute(deps.as_mut(), mock_env(), mock_info(AGENT, &[]), ExecuteMsg::Compound {}).unwrap();
+        let res = reply_withdrawals(&mut deps, &[40]);
+        assert!(res[0].messages.is_empty());
+        assert_eq!(res[0].attributes[1], ("compound_skipped", ContractError::ValidatorsFull { amount: Uint128::new(40) }.to_string()));
+
+        let position = query_position(deps.as_ref(), "1".to_string()).unwrap();
+        assert_eq!((position.bonded, position.rewards), (Uint128::new(100), Uint128::new(40)));
+    }
+
+    #[test]
+    fn share_conversion_rounding() {
+        // 3 shares worth 10 tokens
+        assert_eq!(tokens_to_shares(Uint128::new(5), Uint128::new(10), Uint128::new(3), false).unwrap(), Uint128::new(1));
+        assert_eq!(tokens_to_shares(Uint128::new(5), Uint128::new(10), Uint128::new(3), true).unwrap(), Uint128::new(2));
+        assert_eq!(tokens_to_shares(Uint128::new(10), Uint128::new(10), Uint128::new(3), true).unwrap(), Uint128::new(3));
+        assert_eq!(shares_to_tokens(Uint128::new(2), Uint128::new(10), Uint128::new(3)), Uint128::new(6));
+        // No shares yet
+        assert_eq!(tokens_to_shares(Uint128::new(5), Uint128::zero(), Uint128::zero(), false).unwrap(), Uint128::new(5));
+        assert_eq!(shares_to_tokens(Uint128::new(5), Uint128::zero(), Uint128::zero()), Uint128::zero());
+    }
+
+    #[test]
+    fn weighted_validator_allocation() {
+        let mut deps = mock_dependencies();
+        set_validators(&mut deps.querier);
+        setup_contract(&mut deps, &[]);
+
+        let msg = ExecuteMsg::AddValidator { address: VALIDATOR1.to_string(), bond_denom: "ustake".to_string(), unbonding_period: WEEK, weight: Some(3), max_bonded: None };
+        execute(deps.as_mut(), mock_env(), mock_info(MANAGER, &[]), msg).unwrap();
+        let msg = ExecuteMsg::AddValidator { address: VALIDATOR2.to_string(), bond_denom: "ustake".to_string(), unbonding_period: WEEK, weight: None, max_bonded: None };
+        execute(deps.as_mut(), mock_env(), mock_info(MANAGER, &[]), msg).unwrap();
+
+        let bond = |deps: &mut OwnedDeps<MockStorage, MockApi, MockQuerier>| {
+            let msg = ExecuteMsg::Bond { nft_id: NFT_ID1.to_string() };
+            let res = execute(deps.as_mut(), mock_env(), mock_info(AGENT, &coins(100, "ustake")), msg).unwrap();
+            res.attributes[3].value.clone()
+        };
+        let chosen : Vec<String> = (0..4).map(|_| bond(&mut deps)).collect();
+        assert_eq!(chosen, vec![VALIDATOR1, VALIDATOR1, VALIDATOR2, VALIDATOR1]);
+
+        let msg = ExecuteMsg::SetValidatorWeight { address: VALIDATOR1.to_string(), weight: 0 };
+        let err = execute(deps.as_mut(), mock_env(), mock_info(AGENT, &[]), msg.clone()).unwrap_err();
+        assert_eq!(err, ContractError::Unauthorized {});
+        execute(deps.as_mut(), mock_env(), mock_info(MANAGER, &[]), msg).unwrap();
+        let msg = ExecuteMsg::SetValidatorWeight { address: VALIDATOR3.to_string(), weight: 1 };
+        let err = execute(deps.as_mut(), mock_env(), mock_info(MANAGER, &[]), msg).unwrap_err();
+        assert_eq!(err, ContractError::NotRegisteredValidator { address: VALIDATOR3.to_string() });
+
+        // Weight zero stops new bonds, even far below the other validators
+        assert_eq!(bond(&mut deps), VALIDATOR2);
+        assert_eq!(bond(&mut deps), VALIDATOR2);
+        let msg = ExecuteMsg::SetValidatorWeight { address: VALIDATOR2.to_string(), weight: 0 };
+        execute(deps.as_mut(), mock_env(), mock_info(MANAGER, &[]), msg).unwrap();
+        let msg = ExecuteMsg::Bond { nft_id: NFT_ID1.to_string() };
+        let err = execute(deps.as_mut(), mock_env(), mock_info(AGENT, &coins(100, "ustake")), msg).unwrap_err();
+        assert_eq!(err, ContractError::NoValidatorAvailable {});
+    }
+
+    #[test]
+    fn bond_respects_validator_caps() {
+        let mut deps = mock_dependencies();
+        set_validators(&mut deps.querier);
+        setup_contract(&mut deps, &[]);
+        let msg = ExecuteMsg::AddValidator { address: VALIDATOR1.to_string(), bond_denom: "ustake".to_string(), unbonding_period: WEEK, weight: None, max_bonded: Some(Uint128::new(150)) };
+        execute(deps.as_mut(), mock_env(), mock_info(MANAGER, &[]), msg).unwrap();
+        let msg = ExecuteMsg::AddValidator { address: VALIDATOR2.to_string(), bond_denom: "ustake".to_string(), unbonding_period: WEEK, weight: None, max_bonded: Some(Uint128::new(100)) };
+        execute(deps.as_mut(), mock_env(), mock_info(MANAGER, &[]), msg).unwrap();
+
+        let bond = |deps: &mut OwnedDeps<MockStorage, MockApi, MockQuerier>, amount: u128| {
+            let msg = ExecuteMsg::Bond { nft_id: NFT_ID1.to_string() };
+            execute(deps.as_mut(), mock_env(), mock_info(AGENT, &coins(amount, "ustake")), msg)
+        };
+        let res = bond(&mut deps, 100).unwrap();
+        assert_eq!(res.attributes[3], ("validator", VALIDATOR1));
+        // validator1 would go past its cap although it is the furthest below target
+        let res = bond(&mut deps, 100).unwrap();
+        assert_eq!(res.attributes[3], ("validator", VALIDATOR2));
+        let res = bond(&mut deps, 50).unwrap();
+        assert_eq!(res.attributes[3], ("validator", VALIDATOR1));
+
+        let err = bond(&mut deps, 1).unwrap_err();
+        assert_eq!(err, ContractError::ValidatorsFull { amount: Uint128::new(1) });
+
+        let msg = ExecuteMsg::SetValidatorMaxBonded { address: VALIDATOR2.to_string(), max_bonded: None };
+        let err = execute(deps.as_mut(), mock_env(), mock_info(AGENT, &[]), msg.clone()).unwrap_err();
+        assert_eq!(err, ContractError::Unauthorized {});
+        execute(deps.as_mut(), mock_env(), mock_info(MANAGER, &[]), msg).unwrap();
+        let res = bond(&mut deps, 1000).unwrap();
+        assert_eq!(res.attributes[3], ("validator", VALIDATOR2));
+    }
+
+    #[test]
+    fn split_unbond_strategies() {
+        let validators = vec![
+            (VALIDATOR1.to_string(), 100u128),
+            (VALIDATOR2.to_string(), 300u128),
+            (VALIDATOR3.to_string(), 10u128),
+        ];
+        let split = |strategy, amount| split_unbond(&strategy, &validators, amount);
+
+        assert_eq!(split(UnbondStrategy::Proportional, 205), Some(vec![
+            (VALIDATOR2.to_string(), 150),
+            (VALIDATOR1.to_string(), 50),
+            (VALIDATOR3.to_string(), 5),
+        ]));
+        // The rounding remainder comes from the largest validator
+        assert_eq!(split(UnbondStrategy::Proportional, 7), Some(vec![
+            (VALIDATOR2.to_string(), 6),
+            (VALIDATOR1.to_string(), 1),
+        ]));
+        assert_eq!(split(UnbondStrategy::LargestFirst, 305), Some(vec![
+            (VALIDATOR2.to_string(), 300),
+            (VALIDATOR1.to_string(), 5),
+        ]));
+        // validator3 can not take its third, the rest is split evenly
+        assert_eq!(split(UnbondStrategy::EvenSplit, 205), Some(vec![
+            (VALIDATOR2.to_string(), 98),
+            (VALIDATOR1.to_string(), 97),
+            (VALIDATOR3.to_string(), 10),
+        ]));
+        assert_eq!(split(UnbondStrategy::EvenSplit, 410), Some(vec![
+            (VALIDATOR2.to_string(), 300),
+            (VALIDATOR1.to_string(), 100),
+            (VALIDATOR3.to_string(), 10),
+        ]));
+        assert_eq!(split(UnbondStrategy::Proportional, 411), None);
+        assert_eq!(split(UnbondStrategy::LargestFirst, 0), Some(vec![]));
+        assert_eq!(split_unbond(&UnbondStrategy::EvenSplit, &[], 1), None);
+    }
+
+    #[test]
+    fn split_unbond_always_sums_to_amount() {
+        // Linear congruential generator, enough to explore the inputs without extra dependencies
+        let mut seed = 0x2545_f491_4f6c_dd1du64;
+        let mut next = |max: u128| {
+            seed = seed.wrapping_mul(6_364_136_223_846_793_005).wrapping_add(1_442_695_040_888_963_407);
+            (seed >> 33) as u128 % (max + 1)
+        };
+
+        for _ in 0..2000 {
+            let number_validators = next(8) as usize;
+            let magnitude = [10u128, 1_000, 1_000_000_000_000][next(2) as usize];
+            let validators : Vec<(String, u128)> = (0..number_validators)
+                .map(|i| (format!("validator{}", i), next(magnitude)))
+                .collect();
+            let total : u128 = validators.iter().map(|(_, bonded)| bonded).sum();
+            let amount = next(total + 1);
+
+            for strategy in [UnbondStrategy::Proportional, UnbondStrategy::LargestFirst, UnbondStrategy::EvenSplit] {
+                let split = split_unbond(&strategy, &validators, amount);
+                if amount > total {
+                    assert_eq!(split, None);
+                    continue;
+                }
+                let split = split.unwrap();
+                assert_eq!(split.iter().map(|(_, part)| part).sum::<u128>(), amount, "{} {:?} {}", strategy, validators, amount);
+                for (address, part) in &split {
+                    let bonded = validators.iter().find(|(v, _)| v == address).unwrap().1;
+                    assert!(*part > 0 && *part <= bonded, "{} {:?} {}", strategy, validators, amount);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn furthest_below_target_share() {
+        let candidates = vec![
+            ("a".to_string(), 100u128, 1u64),
+            ("b".to_string(), 250u128, 2u64),
+            ("c".to_string(), 0u128, 0u64),
+        ];
+        // total 350 + 100 -> targets a: 150, b: 300, c: 0
+        assert_eq!(furthest_below_target(&candidates, Uint128::new(100)), Some("a".to_string()));
+        // total 350 + 1000 -> targets a: 450, b: 900
+        assert_eq!(furthest_below_target(&candidates, Uint128::new(1000)), Some("b".to_string()));
+        assert_eq!(furthest_below_target(&[], Uint128::new(1000)), None);
+    }
+
+    #[test]
+    fn remove_validator_redelegates() {
+        let mut deps = mock_dependencies();
+        set_validators(&mut deps.querier);
+        setup_contract(&mut deps, &[VALIDATOR1, VALIDATOR2]);
+
+        let msg = ExecuteMsg::Bond { nft_id: NFT_ID1.to_string() };
+        execute(deps.as_mut(), mock_env(), mock_info(AGENT, &coins(100, "ustake")), msg).unwrap();
+        // 20 ustake more are delegated on chain than recorded
+        deps.querier.update_staking(
+            "ustake",
+            &[sample_validator(VALIDATOR1), sample_validator(VALIDATOR2)],
+            &[sample_delegation(VALIDATOR1, coin(120, "ustake"))],
+        );
+        UNBONDING_ENTRIES.save(deps.as_mut().storage, VALIDATOR1, &vec![Expiration::AtHeight(1)]).unwrap();
+
+        let msg = ExecuteMsg::RemoveValidator { address: VALIDATOR1.to_string() };
+        let res = execute(deps.as_mut(), mock_env(), mock_info(MANAGER, &[]), msg).unwrap();
+        assert_eq!(
+            res.messages[0].msg,
+            CosmosMsg::Staking(StakingMsg::Redelegate { src_validator: VALIDATOR1.to_string(), dst_validator: VALIDATOR2.to_string(), amount: coin(120, "ustake") })
+        );
+        let validator_info: ValidatorInfo = from_binary(&query(deps.as_ref(), mock_env(), QueryMsg::ValidatorInfo { address: VALIDATOR2.to_string() }).unwrap()).unwrap();
+        assert_eq!(validator_info.bonded, 120);
+        assert_eq!(NUMBER_VALIDATORS.load(deps.as_ref().storage).unwrap(), Uint64::new(1));
+        assert!(!UNBONDING_ENTRIES.has(deps.as_ref().storage, VALIDATOR1));
+
+        // The last validator can only be removed without delegations
+        deps.querier.update_staking(
+            "ustake",
+            &[sample_validator(VALIDATOR1), sample_validator(VALIDATOR2)],
+            &[sample_delegation(VALIDATOR2, coin(100, "ustake"))],
+        );
+        let msg = ExecuteMsg::RemoveValidator { address: VALIDATOR2.to_string() };
+        execute(deps.as_mut(), mock_env(), mock_info(MANAGER, &[]), msg.clone()).unwrap_err();
+        set_validators(&mut deps.querier);
+        execute(deps.as_mut(), mock_env(), mock_info(MANAGER, &[]), msg).unwrap();
+    }
+
+    #[test]
+    fn unhealthy_validators_are_skipped() {
+        let mut deps = mock_dependencies();
+        set_validators(&mut deps.querier);
+        setup_contract(&mut deps, &[VALIDATOR1, VALIDATOR2, VALIDATOR3]);
+
+        let msg = ExecuteMsg::SetMaxCommission { max_commission: Decimal::percent(101) };
+        let err = execute(deps.as_mut(), mock_env(), mock_info(MANAGER, &[]), msg).unwrap_err();
+        assert_eq!(err, ContractError::InvalidMaxCommission { max_commission: Decimal::percent(101) });
+        let msg = ExecuteMsg::SetMaxCommission { max_commission: Decimal::percent(5) };
+        let err = execute(deps.as_mut(), mock_env(), mock_info(AGENT, &[]), msg.clone()).unwrap_err();
+        assert_eq!(err, ContractError::Unauthorized {});
+        execute(deps.as_mut(), mock_env(), mock_info(MANAGER, &[]), msg).unwrap();
+
+        // validator1 left the active set and validator2 raised its commission
+        let mut expensive = sample_validator(VALIDATOR2);
+        expensive.commission = Decimal::percent(6);
+        deps.querier.update_staking("ustake", &[expensive, sample_validator(VALIDATOR3)], &[]);
+
+        let msg = ExecuteMsg::Bond { nft_id: NFT_ID1.to_string() };
+        for _ in 0..3 {
+            let res = execute(deps.as_mut(), mock_env(), mock_info(AGENT, &coins(100, "ustake")), msg.clone()).unwrap();
+            assert_eq!(res.attributes[3], ("validator", VALIDATOR3));
+        }
+
+        let res: ValidatorHealthResponse = from_binary(&query(deps.as_ref(), mock_env(), QueryMsg::ValidatorHealth {}).unwrap()).unwrap();
+        assert_eq!(res.validators, vec![
+            ValidatorHealth { address: VALIDATOR1.to_string(), status: ValidatorStatus::Inactive, commission: None },
+            ValidatorHealth { address: VALIDATOR2.to_string(), status: ValidatorStatus::CommissionTooHigh, commission: Some(Decimal::percent(6)) },
+            ValidatorHealth { address: VALIDATOR3.to_string(), status: ValidatorStatus::Healthy, commission: Some(Decimal::percent(3)) },
+        ]);
+
+        deps.querier.update_staking("ustake", &[], &[]);
+        let err = execute(deps.as_mut(), mock_env(), mock_info(AGENT, &coins(100, "ustake")), msg).unwrap_err();
+        assert_eq!(err, ContractError::NoValidatorAvailable {});
+    }
+
+    #[test]
+    fn bond_check() {
+        let mut deps = mock_dependencies();
+        set_validator(&mut deps.querier);
+        setup_contract(&mut deps, &[VALIDATOR1]);
+
+        let msg = ExecuteMsg::Bond { nft_id: NFT_ID1.to_string() };
+        execute(deps.as_mut(), mock_env(), mock_info(AGENT, &coins(100, "ustake")), msg).unwrap();
+
+        set_delegation(&mut deps.querier, 100, "ustake");
+        execute(deps.as_mut(), mock_env(), mock_info(MANAGER, &[]), ExecuteMsg::BondCheck {}).unwrap();
+
+        set_delegation(&mut deps.querier, 90, "ustake");
+        let err = execute(deps.as_mut(), mock_env(), mock_info(MANAGER, &[]), ExecuteMsg::BondCheck {}).unwrap_err();
+        assert_eq!(err, ContractError::BondedDiffer { total_bonded: Uint128::new(90), state_total_bonded: Uint128::new(100) });
+    }
+
+    #[test]
+    fn bond_check_counts_the_pending_unbond_batch() {
+        let mut deps = mock_dependencies();
+        set_validator(&mut deps.querier);
+        setup_contract(&mut deps, &[VALIDATOR1]);
+
+        let msg = ExecuteMsg::Bond { nft_id: NFT_ID1.to_string() };
+        execute(deps.as_mut(), mock_env(), mock_info(AGENT, &coins(400, "ustake")), msg).unwrap();
+        let msg = ExecuteMsg::Unbond { nft_id: NFT_ID1.to_string(), amount: Uint128::new(50) };
+        execute(deps.as_mut(), mock_env(), mock_info(AGENT, &[]), msg).unwrap();
+        assert_eq!(BONDED.load(&deps.storage).unwrap(), Uint128::new(350));
+
+        // The 50 unbonded are still delegated until the batch is processed
+        set_delegation(&mut deps.querier, 400, "ustake");
+        execute(deps.as_mut(), mock_env(), mock_info(MANAGER, &[]), ExecuteMsg::BondCheck {}).unwrap();
     }
 }
\ No newline at end of file
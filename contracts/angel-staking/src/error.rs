@@ -1,4 +1,4 @@
-use cosmwasm_std::{StdError, Uint128, Uint64};
+use cosmwasm_std::{Decimal, OverflowError, StdError, Timestamp, Uint128, Uint64};
 use thiserror::Error;
 
 #[derive(Error, Debug, PartialEq)]
@@ -6,6 +6,9 @@ pub enum ContractError {
     #[error("{0}")]
     Std(#[from] StdError),
 
+    #[error("{0}")]
+    Overflow(#[from] OverflowError),
+
     #[error("Unauthorized")]
     Unauthorized {},
 
@@ -30,6 +33,9 @@ pub enum ContractError {
     #[error("No claims that can be released currently")]
     NothingToClaim {},
 
+    #[error("No rewards to claim for NFT {nft_id}")]
+    NoRewards { nft_id: String },
+
     #[error("Cannot set to own account")]
     CannotSetOwnAccount {},
 
@@ -63,6 +69,66 @@ pub enum ContractError {
     #[error("Unable to unstake {amount} from {number_validators} validators")]
     UnableUnstakeAmount { amount: Uint128, number_validators: Uint64 },
  
+    #[error("Fee of {fee_bps} basis points is above 10000")]
+    InvalidFeeBps { fee_bps: u64 },
+
+    #[error("No surplus to transfer: balance {balance}, owed to NFTs {reserved}")]
+    NoSurplusBalance { balance: Uint128, reserved: Uint128 },
+
+    #[error("Rewards are handled in {mode} mode")]
+    WrongRewardMode { mode: String },
+
+    #[error("Unknown reply id {id}")]
+    UnknownReplyId { id: u64 },
+
+    #[error("Max commission {max_commission} is above 100%")]
+    InvalidMaxCommission { max_commission: Decimal },
+
+    #[error("Every validator is at its max bonded, {amount} can not be bonded")]
+    ValidatorsFull { amount: Uint128 },
+
+    #[error("The unbond batch can not be processed before {ready_at}")]
+    UnbondEpochNotElapsed { ready_at: Timestamp },
+
+    #[error("The unbond batch is empty")]
+    EmptyUnbondBatch {},
+
+    #[error("Validators with a free unbonding entry can not undelegate {amount}")]
+    NoUnbondingEntryAvailable { amount: Uint128 },
+
+    #[error("Can not migrate from contract {contract}")]
+    WrongContract { contract: String },
+
+    #[error("Invalid contract version {version}")]
+    InvalidVersion { version: String },
+
+    #[error("Can not migrate from version {from} down to {to}")]
+    CannotDowngrade { from: String, to: String },
+
+    #[error("nft_contract must be set to migrate from this version")]
+    MissingNftContract {},
+
+    #[error("{address} is not proposed for {role}")]
+    NoPendingProposal { role: String, address: String },
+
+    #[error("The proposal for {role} has expired")]
+    ProposalExpired { role: String },
+
+    #[error("Agent {address} not registered")]
+    NotRegisteredAgent { address: String },
+
+    #[error("The contract is paused")]
+    Paused {},
+
+    #[error("No validators registered")]
+    NoValidatorsRegistered {},
+
+    #[error("No registered validator can receive new bonds")]
+    NoValidatorAvailable {},
+
+    #[error("NFT {nft_id} has {bonded} bonded, can not unbond {amount}")]
+    UnbondExceedsPosition { nft_id: String, bonded: Uint128, amount: Uint128 },
+
     #[error("Validator {address} not registered")]
     NotRegisteredValidator { address: String },
 
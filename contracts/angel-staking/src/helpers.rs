@@ -2,10 +2,10 @@ use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 
 use cosmwasm_std::{
-    to_binary, Addr, CosmosMsg, CustomQuery, Querier, QuerierWrapper, StdResult, WasmMsg, WasmQuery,
+    to_binary, Addr, CosmosMsg, StdResult, WasmMsg,
 };
 
-use crate::msg::{ExecuteMsg, QueryMsg};
+use crate::msg::ExecuteMsg;
 
 
 /// CwTemplateContract is a wrapper around Addr that provides a lot of helpers
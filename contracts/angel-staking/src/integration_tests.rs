@@ -1,27 +1,71 @@
 #[cfg(test)]
 mod tests {
-    use crate::msg::{ExecuteMsg};
-    use cosmwasm_std::{coin, coins, to_binary, Addr, Coin, Empty, Uint128};
-    use cw_multi_test::{App, AppBuilder, Contract, ContractWrapper, Executor};
+    use crate::msg::{ExecuteMsg, InstantiateMsg, PositionResponse, QueryMsg, ReceiveNftMsg};
+    use crate::state::ValidatorInfo;
+    use cosmwasm_std::testing::mock_env;
+    use cosmwasm_std::{coins, to_binary, Addr, Coin, Decimal, Empty, Uint128, Validator};
+    use cw721::{Cw721QueryMsg, OwnerOfResponse};
+    use cw_multi_test::{App, AppBuilder, Contract, ContractWrapper, Executor, StakingInfo, StakingSudo, SudoMsg};
+    use cw_utils::WEEK;
 
     const USER1: &str = "juno10c3slrqx3369mfsr9670au22zvq082jaej8ve4";
     const USER2: &str = "juno10c3slrqx3369mfsr9670au22zvq082jaejxx23";
-    const ADMIN: &str = "ADMIN";
+    const ADMIN: &str = "admin";
     const NATIVE_DENOM: &str = "ujunox";
     const TOKEN_ID: &str = "0";
     const MINTER: &str = "juno10c3slrqx3369mfsr9670au22zvq082jaejxx85";
+    const OWNER: &str = "owner";
+    const NEW_OWNER: &str = "new_owner";
+    // unbonding period of the registered validators
+    const WEEK_SECONDS: u64 = 7 * 24 * 60 * 60;
+    const VALIDATOR1: &str = "validator1";
+    const VALIDATOR2: &str = "validator2";
 
     pub fn contract_nft() -> Box<dyn Contract<Empty>> {
         let contract = ContractWrapper::new(
             crate::contract::execute,
             crate::contract::instantiate,
             crate::contract::query,
+        )
+        .with_reply(crate::contract::reply);
+        Box::new(contract)
+    }
+
+    pub fn contract_cw721() -> Box<dyn Contract<Empty>> {
+        let contract = ContractWrapper::new(
+            cw721_base::entry::execute,
+            cw721_base::entry::instantiate,
+            cw721_base::entry::query,
         );
         Box::new(contract)
     }
 
+    fn sample_validator(addr: &str) -> Validator {
+        Validator {
+            address: addr.into(),
+            commission: Decimal::percent(3),
+            max_commission: Decimal::percent(10),
+            max_change_rate: Decimal::percent(1),
+        }
+    }
+
     fn mock_app() -> App {
-        AppBuilder::new().build(|router, _, storage| {
+        AppBuilder::new().build(|router, api, storage| {
+            let block = mock_env().block;
+            router
+                .staking
+                .setup(storage, StakingInfo {
+                    bonded_denom: NATIVE_DENOM.to_string(),
+                    unbonding_time: 60,
+                    apr: Decimal::percent(10),
+                })
+                .unwrap();
+            for validator in [VALIDATOR1, VALIDATOR2] {
+                router
+                    .staking
+                    .add_validator(api, storage, &block, sample_validator(validator))
+                    .unwrap();
+            }
             router
                 .bank
                 .init_balance(
@@ -53,95 +97,288 @@ mod tests {
         (app, code_id_nft)
     }
 
-    // pub fn cw721_instantiate(app: &mut App, code_id: u64, name: String, symbol: String, minter: String,) -> NftContract {
-    //     let contract = app
-    //         .instantiate_contract(
-    //             code_id,
-    //             Addr::unchecked(ADMIN),
-    //             &cw721_base::InstantiateMsg {                   
-    //                 name,
-    //                 symbol,
-    //                 minter,
-    //             },
-    //             &[],
-    //             "nft",
-    //             None,
-    //         )
-    //         .unwrap();
-    //     NftContract(contract)
-    // }
-
-    // let mint_msg = MintMsg {
-    //     token_id: token_id.to_string(),
-    //     owner: "bob".to_string(),
-    //     token_uri: None,
-    //     extension: Metadata {
-    //         native: Some(coins(1000, "earth")),
-    //         cw20: None,
-    //     },
-    // };
-
-    // fn get_owner_of(app: &App, nft_contract: &NftContract, token_id: String) -> OwnerOfResponse {
-    //     app.wrap()
-    //         .query_wasm_smart(
-    //             nft_contract.addr(),
-    //             &crate::contract::QueryMsg::OwnerOf {
-    //                 token_id,
-    //                 include_expired: None,
-    //             },
-    //         )
-    //         .unwrap()
-    // }
-
-    // // Only MINTER can mint.
-    // fn mint_nft(app: &mut App, cw721_contract: &NftContract, token_id: String, token_uri: Option<String>, owner: String, extension:Metadata) -> () {
-    //     let mint_msg = cw721_base::MintMsg {
-    //         token_id,
-    //         owner,
-    //         token_uri,
-    //         extension,
-    //     };
-    //     let msg = crate::contract::Cw721ExecuteMsg::Mint(mint_msg);
-    //     let cosmos_msg = cw721_contract.call(msg).unwrap();
-    //     app.execute(Addr::unchecked(MINTER), cosmos_msg).unwrap();
-    // }
-
-    // #[test]
-    // fn instantiate_mint_nft() {
-    //     let (mut app, code_id_cw721) = store_code();
-    //     let nft_contract = cw721_instantiate(
-    //         &mut app,
-    //         code_id_cw721,
-    //         "Greeks".to_string(),
-    //         "draghma".to_string(),
-    //         MINTER.to_string(),
-    //     );
-
-    //     let metadata = Metadata{ 
-    //         native: Some(coins(1000, NATIVE_DENOM)), 
-    //         cw20: None };
-    //     //mint a new NFT with token_id "0"
-    //     mint_nft(
-    //         &mut app,
-    //         &nft_contract,
-    //         TOKEN_ID.to_string(),
-    //         Some("url".to_string()),
-    //         USER1.to_string(),
-    //         metadata,
-    //     );
-
-    //      //get owner of NFT with token_id "0"
-    //     let owner = get_owner_of(&app, &nft_contract, TOKEN_ID.to_string());
-    //     assert_eq!(owner.owner, USER1.to_string());
-
-    //     let new_metadata = Metadata{ 
-    //         native: Some(coins(2000, NATIVE_DENOM)), 
-    //         cw20: None };
-            
-    //     // let msg:ExecuteMsg<Metadata> = crate::msg::ExecuteMsg::UpdateMetadata { token_id: TOKEN_ID.to_string(), token_uri: "url2".to_string(), metadata: new_metadata };
-    //     // let cosmos_msg = nft_contract.call(msg).unwrap();
-    //     // app.execute(Addr::unchecked(MINTER), cosmos_msg).unwrap();        
-
-    // }
-
-}
\ No newline at end of file
+    // A cw721 collection minted by MINTER
+    fn instantiate_nft(app: &mut App) -> Addr {
+        let code_id = app.store_code(contract_cw721());
+        app.instantiate_contract(
+            code_id,
+            Addr::unchecked(ADMIN),
+            &cw721_base::InstantiateMsg {
+                name: "Greeks".to_string(),
+                symbol: "draghma".to_string(),
+                minter: MINTER.to_string(),
+            },
+            &[],
+            "nft",
+            None,
+        )
+        .unwrap()
+    }
+
+    fn mint_nft(app: &mut App, nft_contract: &Addr, token_id: &str, owner: &str) {
+        let msg: cw721_base::ExecuteMsg<cw721_base::Extension, Empty> = cw721_base::ExecuteMsg::Mint(cw721_base::MintMsg {
+            token_id: token_id.to_string(),
+            owner: owner.to_string(),
+            token_uri: None,
+            extension: None,
+        });
+        app.execute_contract(Addr::unchecked(MINTER), nft_contract.clone(), &msg, &[]).unwrap();
+    }
+
+    // USER1 acts as agent, ADMIN as manager and USER2 as treasury. Returns the staking and the NFT contracts
+    fn instantiate_staking(app: &mut App, code_id: u64) -> (Addr, Addr) {
+        let nft_contract = instantiate_nft(app);
+        let contract = app
+            .instantiate_contract(
+                code_id,
+                Addr::unchecked(ADMIN),
+                &InstantiateMsg {
+                    agent: USER1.to_string(),
+                    manager: ADMIN.to_string(),
+                    treasury: USER2.to_string(),
+                    nft_contract: nft_contract.to_string(),
+                },
+                &[],
+                "angel-staking",
+                None,
+            )
+            .unwrap();
+
+        for validator in [VALIDATOR1, VALIDATOR2] {
+            app.execute_contract(
+                Addr::unchecked(ADMIN),
+                contract.clone(),
+                &ExecuteMsg::AddValidator {
+                    address: validator.to_string(),
+                    bond_denom: NATIVE_DENOM.to_string(),
+                    unbonding_period: WEEK,
+                    weight: None,
+                    max_bonded: None,
+                },
+                &[],
+            )
+            .unwrap();
+        }
+        (contract, nft_contract)
+    }
+
+    #[test]
+    fn bond_delegates_to_least_bonded_validator() {
+        let (mut app, code_id) = store_code();
+        let (contract, _) = instantiate_staking(&mut app, code_id);
+        let nft_id = TOKEN_ID.to_string();
+
+        for amount in [1000u128, 400u128] {
+            app.execute_contract(
+                Addr::unchecked(USER1),
+                contract.clone(),
+                &ExecuteMsg::Bond { nft_id: nft_id.clone() },
+                &coins(amount, NATIVE_DENOM),
+            )
+            .unwrap();
+        }
+
+        // Every validator holds on chain exactly what the contract recorded for it
+        for validator in [VALIDATOR1, VALIDATOR2] {
+            let info: ValidatorInfo = app
+                .wrap()
+                .query_wasm_smart(contract.clone(), &QueryMsg::ValidatorInfo { address: validator.to_string() })
+                .unwrap();
+            let delegation = app.wrap().query_delegation(contract.clone(), validator).unwrap().unwrap();
+            assert_eq!(delegation.amount.amount.u128(), info.bonded);
+        }
+
+        let bonded: Uint128 = app
+            .wrap()
+            .query_wasm_smart(contract.clone(), &QueryMsg::ContractBonded {})
+            .unwrap();
+        assert_eq!(bonded, Uint128::new(1400));
+        assert_eq!(
+            app.wrap().query_balance(USER1, NATIVE_DENOM).unwrap().amount,
+            Uint128::new(8600)
+        );
+    }
+
+    #[test]
+    fn validator_info_matches_chain_after_unbond() {
+        let (mut app, code_id) = store_code();
+        let (contract, _) = instantiate_staking(&mut app, code_id);
+        app.execute_contract(Addr::unchecked(ADMIN), contract.clone(), &ExecuteMsg::SetUnbondEpoch { epoch: 0 }, &[])
+            .unwrap();
+
+        let cycles = [
+            (1u128, 1000u128, 300u128),
+            (2u128, 2500u128, 2500u128),
+            (1u128, 700u128, 1399u128),
+            (3u128, 3u128, 1u128),
+        ];
+        for (nft_id, bond, unbond) in cycles {
+            app.execute_contract(
+                Addr::unchecked(USER1),
+                contract.clone(),
+                &ExecuteMsg::Bond { nft_id: nft_id.to_string() },
+                &coins(bond, NATIVE_DENOM),
+            )
+            .unwrap();
+            app.execute_contract(
+                Addr::unchecked(USER1),
+                contract.clone(),
+                &ExecuteMsg::Unbond { nft_id: nft_id.to_string(), amount: unbond.into() },
+                &[],
+            )
+            .unwrap();
+            app.execute_contract(Addr::unchecked(USER1), contract.clone(), &ExecuteMsg::ProcessUnbondBatch {}, &[])
+                .unwrap();
+
+            for validator in [VALIDATOR1, VALIDATOR2] {
+                let info: ValidatorInfo = app
+                    .wrap()
+                    .query_wasm_smart(contract.clone(), &QueryMsg::ValidatorInfo { address: validator.to_string() })
+                    .unwrap();
+                let on_chain = app
+                    .wrap()
+                    .query_delegation(contract.clone(), validator)
+                    .unwrap()
+                    .map(|delegation| delegation.amount.amount.u128())
+                    .unwrap_or_default();
+                assert_eq!(on_chain, info.bonded);
+            }
+        }
+
+        let bonded: Uint128 = app
+            .wrap()
+            .query_wasm_smart(contract.clone(), &QueryMsg::ContractBonded {})
+            .unwrap();
+        assert_eq!(bonded, Uint128::new(3));
+        let claimed: Uint128 = app
+            .wrap()
+            .query_wasm_smart(contract, &QueryMsg::ContractClaimed {})
+            .unwrap();
+        assert_eq!(claimed, Uint128::new(4200));
+    }
+
+    #[test]
+    fn claim_pays_the_nft_owner() {
+        let (mut app, code_id) = store_code();
+        let (contract, nft_contract) = instantiate_staking(&mut app, code_id);
+        mint_nft(&mut app, &nft_contract, TOKEN_ID, OWNER);
+        app.execute_contract(Addr::unchecked(ADMIN), contract.clone(), &ExecuteMsg::SetUnbondEpoch { epoch: 0 }, &[])
+            .unwrap();
+
+        let nft_id = TOKEN_ID.to_string();
+        app.execute_contract(Addr::unchecked(USER1), contract.clone(), &ExecuteMsg::Bond { nft_id: nft_id.clone() }, &coins(1000, NATIVE_DENOM))
+            .unwrap();
+        app.execute_contract(Addr::unchecked(USER1), contract.clone(), &ExecuteMsg::Unbond { nft_id: nft_id.clone(), amount: Uint128::new(400) }, &[])
+            .unwrap();
+        app.execute_contract(Addr::unchecked(USER1), contract.clone(), &ExecuteMsg::ProcessUnbondBatch {}, &[])
+            .unwrap();
+
+        app.update_block(|block| block.time = block.time.plus_seconds(WEEK_SECONDS));
+        app.sudo(SudoMsg::Staking(StakingSudo::ProcessQueue {})).unwrap();
+
+        // The NFT changed hands after the unbond, the claim follows it
+        let transfer: cw721_base::ExecuteMsg<cw721_base::Extension, Empty> = cw721_base::ExecuteMsg::TransferNft {
+            recipient: NEW_OWNER.to_string(),
+            token_id: TOKEN_ID.to_string(),
+        };
+        app.execute_contract(Addr::unchecked(OWNER), nft_contract, &transfer, &[]).unwrap();
+
+        app.execute_contract(Addr::unchecked(USER1), contract, &ExecuteMsg::Claim { nft_id }, &[])
+            .unwrap();
+        assert_eq!(app.wrap().query_balance(NEW_OWNER, NATIVE_DENOM).unwrap().amount, Uint128::new(400));
+        assert_eq!(app.wrap().query_balance(OWNER, NATIVE_DENOM).unwrap().amount, Uint128::zero());
+    }
+
+    #[test]
+    fn escrowed_nft_is_released_after_unbond_and_claim() {
+        let (mut app, code_id) = store_code();
+        let (contract, nft_contract) = instantiate_staking(&mut app, code_id);
+        mint_nft(&mut app, &nft_contract, TOKEN_ID, USER2);
+        app.execute_contract(Addr::unchecked(ADMIN), contract.clone(), &ExecuteMsg::SetUnbondEpoch { epoch: 0 }, &[])
+            .unwrap();
+
+        // Lock the NFT and stake for it, without the agent
+        let send: cw721_base::ExecuteMsg<cw721_base::Extension, Empty> = cw721_base::ExecuteMsg::SendNft {
+            contract: contract.to_string(),
+            token_id: TOKEN_ID.to_string(),
+            msg: to_binary(&ReceiveNftMsg::Stake {}).unwrap(),
+        };
+        app.execute_contract(Addr::unchecked(USER2), nft_contract.clone(), &send, &[]).unwrap();
+        let nft_id = TOKEN_ID.to_string();
+        app.execute_contract(Addr::unchecked(USER2), contract.clone(), &ExecuteMsg::Bond { nft_id: nft_id.clone() }, &coins(1000, NATIVE_DENOM))
+            .unwrap();
+
+        let owner: OwnerOfResponse = app
+            .wrap()
+            .query_wasm_smart(&nft_contract, &Cw721QueryMsg::OwnerOf { token_id: TOKEN_ID.to_string(), include_expired: None })
+            .unwrap();
+        assert_eq!(owner.owner, contract.to_string());
+
+        app.execute_contract(Addr::unchecked(USER2), contract.clone(), &ExecuteMsg::Unbond { nft_id: nft_id.clone(), amount: Uint128::new(1000) }, &[])
+            .unwrap();
+        app.execute_contract(Addr::unchecked(USER1), contract.clone(), &ExecuteMsg::ProcessUnbondBatch {}, &[])
+            .unwrap();
+        app.update_block(|block| block.time = block.time.plus_seconds(WEEK_SECONDS));
+        app.sudo(SudoMsg::Staking(StakingSudo::ProcessQueue {})).unwrap();
+
+        app.execute_contract(Addr::unchecked(USER2), contract.clone(), &ExecuteMsg::Claim { nft_id }, &[])
+            .unwrap();
+        assert_eq!(app.wrap().query_balance(USER2, NATIVE_DENOM).unwrap().amount, Uint128::new(10000));
+        let owner: OwnerOfResponse = app
+            .wrap()
+            .query_wasm_smart(&nft_contract, &Cw721QueryMsg::OwnerOf { token_id: TOKEN_ID.to_string(), include_expired: None })
+            .unwrap();
+        assert_eq!(owner.owner, USER2.to_string());
+        let depositor: Option<Addr> = app
+            .wrap()
+            .query_wasm_smart(contract, &QueryMsg::EscrowedNft { nft_id: TOKEN_ID.to_string() })
+            .unwrap();
+        assert_eq!(depositor, None);
+    }
+
+    #[test]
+    fn collect_rewards_records_realised_amount() {
+        let (mut app, code_id) = store_code();
+        let (contract, _) = instantiate_staking(&mut app, code_id);
+
+        for (nft_id, amount) in [(1u128, 1000u128), (2u128, 3000u128)] {
+            app.execute_contract(
+                Addr::unchecked(USER1),
+                contract.clone(),
+                &ExecuteMsg::Bond { nft_id: nft_id.to_string() },
+                &coins(amount, NATIVE_DENOM),
+            )
+            .unwrap();
+        }
+
+        // One year at 10% APR
+        app.update_block(|block| block.time = block.time.plus_seconds(60 * 60 * 24 * 365));
+        let res = app
+            .execute_contract(Addr::unchecked(ADMIN), contract.clone(), &ExecuteMsg::CollectAngelRewards {}, &[])
+            .unwrap();
+        assert!(res.has_event(&cosmwasm_std::Event::new("wasm-realised_rewards").add_attribute("validator", VALIDATOR1)));
+
+        let total_rewards: Uint128 = app
+            .wrap()
+            .query_wasm_smart(contract.clone(), &QueryMsg::TotalRewards {})
+            .unwrap();
+        assert!(!total_rewards.is_zero());
+        assert_eq!(
+            app.wrap().query_balance(contract.clone(), NATIVE_DENOM).unwrap().amount,
+            total_rewards
+        );
+
+        // Rewards are credited pro-rata to the bonded amount
+        let position1: PositionResponse = app
+            .wrap()
+            .query_wasm_smart(contract.clone(), &QueryMsg::Position { nft_id: "1".to_string() })
+            .unwrap();
+        let position2: PositionResponse = app
+            .wrap()
+            .query_wasm_smart(contract, &QueryMsg::Position { nft_id: "2".to_string() })
+            .unwrap();
+        assert_eq!(position1.rewards + position2.rewards, total_rewards);
+        assert_eq!(position1.rewards * Uint128::new(3), position2.rewards);
+    }
+
+}
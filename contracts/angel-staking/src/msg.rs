@@ -1,39 +1,109 @@
 use cosmwasm_schema::{cw_serde, QueryResponses};
 
-use cosmwasm_std::{Uint128, Coin};
+use cosmwasm_std::{Uint128, Coin, Decimal, Timestamp};
 pub use cw_controllers::ClaimsResponse;
-use cw_utils::Duration;
-use crate::state::{ValidatorInfo};
+use cw_utils::{Duration, Expiration};
+use cw721::Cw721ReceiveMsg;
+use crate::state::{ValidatorInfo, RewardMode, UnbondStrategy, TokenId, Role, AgentPermissions};
 
 #[cw_serde]
 pub struct InstantiateMsg {
    pub agent: String,	
    pub manager: String, 
    pub treasury: String,
+   /// cw721 collection of the NFTs. Claims and rewards are paid to the owner of the NFT
+   pub nft_contract: String,
+}
+
+#[cw_serde]
+pub struct MigrateMsg {
+   /// cw721 collection of the NFTs. Required when the deployed version does not store it yet
+   pub nft_contract: Option<String>,
 }
 
 #[cw_serde]
 pub enum ExecuteMsg {
     /// Bond will bond all staking tokens sent with the message
-    Bond {nft_id: Uint128},
+    Bond {nft_id: TokenId},
+    /// ReceiveNft escrows an NFT sent with SendNft of the cw721 collection. Its depositor can then Bond, Unbond and Claim for it.
+    /// The NFT is sent back with the Claim that leaves nothing bonded or to claim for it
+    ReceiveNft(Cw721ReceiveMsg),
     /// Unbond staking tokens set by amount
-    Unbond { nft_id: Uint128, amount: Uint128 },
-    /// Claim is used to claim native tokens previously "unbonded" after the chain-defined unbonding period
-    Claim {nft_id: Uint128 , sender: String},
-    AddValidator {address: String, bond_denom: String, unbonding_period: Duration},
+    Unbond { nft_id: TokenId, amount: Uint128 },
+    /// Claim is used to claim native tokens previously "unbonded" after the chain-defined unbonding period.
+    /// They are sent to the owner of the NFT in the cw721 collection
+    Claim {nft_id: TokenId},
+    /// AddValidator registers a validator. Bonds go to the validator furthest below its share of the total weight (1 by default)
+    /// A validator with max_bonded never gets bonds that would take it past that amount
+    AddValidator {address: String, bond_denom: String, unbonding_period: Duration, weight: Option<u64>, max_bonded: Option<Uint128>},
+    /// SetValidatorWeight changes the target weight of a registered validator. Weight zero stops new bonds to it
+    SetValidatorWeight {address: String, weight: u64},
+    /// SetValidatorMaxBonded changes the cap of a registered validator. None removes it
+    SetValidatorMaxBonded {address: String, max_bonded: Option<Uint128>},
+    /// SetMaxCommission stops new bonds to validators charging a higher commission
+    SetMaxCommission { max_commission: Decimal },
     RemoveValidator {address: String},
     BondCheck {},
+    /// SyncSlashing lowers the bonded amount of the validators whose delegation was slashed.
+    /// The loss is shared by the bonded NFTs through the value of their shares
+    SyncSlashing {},
+    /// CollectAngelRewards withdraws the rewards from all validators and credits them to the bonded NFTs
     CollectAngelRewards {},
+    /// ClaimRewards sends the rewards credited to an NFT to its owner
+    ClaimRewards { nft_id: TokenId },
     TransferBalanceToTreasury{},
+    /// SetFeeBps sets the share of the collected rewards, in basis points, sent to the treasury
+    SetFeeBps { fee_bps: u64 },
+    /// Compound withdraws the rewards from all validators and delegates them again
+    Compound {},
+    /// SetRewardMode chooses between paying out the rewards (CollectAngelRewards) or compounding them (Compound)
+    SetRewardMode { mode: RewardMode },
+    /// SetUnbondStrategy chooses how an unbond is split between the validators
+    SetUnbondStrategy { strategy: UnbondStrategy },
+    /// ProcessUnbondBatch undelegates the unbonds collected in the pending batch, once its epoch has elapsed
+    ProcessUnbondBatch {},
+    /// SetUnbondEpoch sets the seconds a batch collects unbonds before it can be undelegated
+    SetUnbondEpoch { epoch: u64 },
+    /// SetSelfService lets the owner of an NFT call Bond, Unbond, Claim and ClaimRewards for it, besides the agents
+    SetSelfService { enabled: bool },
+    /// ProposeManager proposes a new manager, who takes the role with AcceptManager before expires
+    ProposeManager { address: String, expires: Option<Expiration> },
+    /// AcceptManager drops every other pending proposal, they were made by the previous manager
+    AcceptManager {},
+    /// ProposeAgent proposes an agent, who joins the agent set with permissions through AcceptAgent before expires
+    ProposeAgent { address: String, permissions: AgentPermissions, expires: Option<Expiration> },
+    AcceptAgent {},
+    /// SetAgentPermissions changes what a registered agent can do
+    SetAgentPermissions { address: String, permissions: AgentPermissions },
+    RemoveAgent { address: String },
+    /// ProposeTreasury proposes a new treasury, which takes the role with AcceptTreasury before expires
+    ProposeTreasury { address: String, expires: Option<Expiration> },
+    AcceptTreasury {},
+    /// SetGuardian sets the address that can pause the contract, besides the manager. None removes it
+    SetGuardian { address: Option<String> },
+    /// Pause rejects Bond, Unbond and staking with ReceiveNft until Unpause. Claims keep working
+    Pause {},
+    /// Unpause is only allowed to the manager
+    Unpause {},
 }
 
 
+/// Message sent with an NFT to this contract
+#[cw_serde]
+pub enum ReceiveNftMsg {
+    /// Escrow the NFT to stake for it
+    Stake {},
+}
+
 #[cw_serde]
 #[derive(QueryResponses)]
 pub enum QueryMsg {
     /// Claims shows the number of tokens this address can access when they are done unbonding.
     #[returns(ClaimsResponse)]
-    Claims { nft_id: String },
+    Claims { nft_id: TokenId },
+    /// ClaimStatus sums the claims of an NFT as of the current block
+    #[returns(ClaimStatusResponse)]
+    ClaimStatus { nft_id: TokenId },
     #[returns(ValidatorInfo)]
     ValidatorInfo {address: String},
     #[returns(Uint128)]
@@ -46,12 +116,183 @@ pub enum QueryMsg {
     ContractClaimed {},
     #[returns(Uint128)]
     BondedOnValidator {address: String},    
+    /// Agent returns the first agent of the set by address. Deprecated, use ListAgents
     #[returns(String)]
-    Agent {},   
+    Agent {},
+    /// ListAgents lists the agent set with the permissions of every agent, ordered by address
+    #[returns(ListAgentsResponse)]
+    ListAgents { start_after: Option<String>, limit: Option<u32> },
     #[returns(String)]
     Manager {},
+    #[returns(String)]
+    Treasury {},
+    /// PendingProposals lists the addresses proposed for a role that have not accepted it yet
+    #[returns(PendingProposalsResponse)]
+    PendingProposals {},
+    #[returns(cosmwasm_std::Addr)]
+    NftContract {},
+    #[returns(bool)]
+    SelfService {},
+    #[returns(Option<cosmwasm_std::Addr>)]
+    Guardian {},
+    #[returns(bool)]
+    Paused {},
+    /// EscrowedNft returns the depositor of an NFT escrowed with ReceiveNft
+    #[returns(Option<cosmwasm_std::Addr>)]
+    EscrowedNft { nft_id: TokenId },
+    #[returns(u64)]
+    FeeBps {},
+    #[returns(RewardMode)]
+    RewardMode {},
+    #[returns(UnbondStrategy)]
+    UnbondStrategy {},
+    #[returns(u64)]
+    UnbondEpoch {},
+    /// UnbondBatch returns a batch by id, the pending one when id is None
+    #[returns(UnbondBatchResponse)]
+    UnbondBatch { id: Option<u64> },
+    /// UnbondingEntries lists the release time of the undelegations from a validator still unbonding
+    #[returns(Vec<Expiration>)]
+    UnbondingEntries { address: String },
+    /// ValidatorRewards shows the rewards realised from a validator since instantiation
+    #[returns(Uint128)]
+    ValidatorRewards { address: String },
+    /// TotalRewards shows the rewards realised from all validators since instantiation
+    #[returns(Uint128)]
+    TotalRewards {},
+    #[returns(Decimal)]
+    MaxCommission {},
+    /// ValidatorHealth shows which registered validators are skipped when bonding and why
+    #[returns(ValidatorHealthResponse)]
+    ValidatorHealth {},
     #[returns(Coin)]
-    RewardsBalance {},       
+    RewardsBalance {},
+    /// Reconciliation compares the delegations on chain with the bonded amounts stored for every validator
+    #[returns(ReconciliationResponse)]
+    Reconciliation {},       
+    /// Position shows the tokens bonded, unbonding and earned by an NFT
+    #[returns(PositionResponse)]
+    Position { nft_id: TokenId },
+    /// AllPositions lists the position of every NFT that has bonded, ordered by nft_id
+    #[returns(AllPositionsResponse)]
+    AllPositions { start_after: Option<TokenId>, limit: Option<u32> },
+}
+
+#[cw_serde]
+pub struct PositionResponse {
+    pub nft_id: TokenId,
+    /// Tokens currently bonded, including compounded rewards
+    pub bonded: Uint128,
+    /// Shares of the contract bonded tokens
+    pub shares: Uint128,
+    /// Tokens unbonded and waiting to be claimed
+    pub pending_claims: Uint128,
+    /// Rewards earned and not claimed yet
+    pub rewards: Uint128,
+    /// Time of the first bond
+    pub bonded_since: Timestamp,
+}
+
+#[cw_serde]
+pub struct ClaimStatusResponse {
+    /// Every claim not claimed yet, matured or not
+    pub pending: Uint128,
+    /// Matured claims, the amount Claim would send now
+    pub claimable: Uint128,
+    /// Earliest release of the claims not matured. Claims of a batch not undelegated yet have no release time
+    pub next_release: Option<Expiration>,
+}
+
+#[cw_serde]
+pub struct UnbondBatchResponse {
+    pub id: u64,
+    pub total: Uint128,
+    pub started_at: Timestamp,
+    /// None while the batch is collecting unbonds
+    pub release_at: Option<Expiration>,
+}
+
+#[cw_serde]
+pub enum ValidatorStatus {
+    /// Can receive new bonds
+    Healthy,
+    /// Not in the active validator set (jailed, tombstoned or unbonded)
+    Inactive,
+    /// Commission above the maximum set by the manager
+    CommissionTooHigh,
+}
+
+#[cw_serde]
+pub struct ValidatorHealth {
+    pub address: String,
+    pub status: ValidatorStatus,
+    /// Current commission, unknown for validators out of the active set
+    pub commission: Option<Decimal>,
+}
+
+#[cw_serde]
+pub struct ValidatorHealthResponse {
+    pub validators: Vec<ValidatorHealth>,
+}
+
+#[cw_serde]
+pub struct RoleProposalResponse {
+    pub role: Role,
+    pub address: String,
+    pub permissions: Option<AgentPermissions>,
+    pub expires: Option<Expiration>,
+}
+
+#[cw_serde]
+pub struct PendingProposalsResponse {
+    pub proposals: Vec<RoleProposalResponse>,
+}
+
+#[cw_serde]
+pub struct ValidatorReconciliation {
+    pub address: String,
+    /// Delegated to the validator on chain
+    pub delegated: Uint128,
+    /// ValidatorInfo.bonded
+    pub bonded: Uint128,
+    /// bonded minus delegated when the chain has less, as after a slash
+    pub shortfall: Uint128,
+    /// delegated minus bonded when the chain has more
+    pub surplus: Uint128,
+}
+
+#[cw_serde]
+pub struct ReconciliationResponse {
+    pub validators: Vec<ValidatorReconciliation>,
+    pub total_delegated: Uint128,
+    /// Sum of the bonded of every validator, BONDED plus the pending unbond batch
+    pub total_bonded: Uint128,
+    /// BONDED, the tokens of the NFT positions
+    pub contract_bonded: Uint128,
+    /// Unbonded by the NFTs and still delegated until the pending batch is processed
+    pub pending_unbond: Uint128,
+    /// Bonded denom balance of the contract
+    pub balance: Uint128,
+    /// Part of the balance owed to the NFTs, released claims and rewards not claimed yet
+    pub reserved: Uint128,
+    /// Balance over the reserved, what TransferBalanceToTreasury would send
+    pub spare_balance: Uint128,
+}
+
+#[cw_serde]
+pub struct AgentResponse {
+    pub address: String,
+    pub permissions: AgentPermissions,
+}
+
+#[cw_serde]
+pub struct ListAgentsResponse {
+    pub agents: Vec<AgentResponse>,
+}
+
+#[cw_serde]
+pub struct AllPositionsResponse {
+    pub positions: Vec<PositionResponse>,
 }
 
 
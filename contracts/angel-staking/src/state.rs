@@ -1,8 +1,10 @@
+use std::fmt;
+
 use cosmwasm_schema::cw_serde;
-use cosmwasm_std::{ Uint128, Uint64};
-use cw_controllers::Claims;
-use cw_storage_plus::{Item, MultiIndex, Index, IndexList, IndexedMap};
-use cw_utils::Duration;
+use cosmwasm_std::{ Addr, Decimal, Timestamp, Uint128, Uint64};
+use cw_controllers::Claim;
+use cw_storage_plus::{Item, Map, MultiIndex, Index, IndexList, IndexedMap};
+use cw_utils::{Duration, Expiration};
 
 
 
@@ -23,14 +25,244 @@ pub const TOTAL_CLAIMED: Item<Uint128> = Item::new("total_claimed");
 pub const NUMBER_VALIDATORS: Item<Uint64> = Item::new("number_validators");
 
 // Addresses
-pub const AGENT: Item<String> = Item::new("relayer");
 pub const MANAGER: Item<String> = Item::new("manager");
 pub const TREASURY: Item<String> = Item::new("treasury");
 
+// Address allowed to pause the contract besides the manager
+pub const GUARDIAN: Item<Addr> = Item::new("guardian");
+
+// While paused, new bonds and unbonds are rejected
+pub const PAUSED: Item<bool> = Item::new("paused");
+
+// Single agent of the versions before the agent set, moved to AGENTS by the migration
+pub const LEGACY_AGENT: Item<String> = Item::new("relayer");
+
+// Agents acting for the NFTs, with what each one can do. pk: agent address
+pub const AGENTS: Map<&Addr, AgentPermissions> = Map::new("agents");
+
+#[cw_serde]
+pub struct AgentPermissions {
+    /// Bond for any NFT
+    pub bond: bool,
+    /// Unbond for any NFT and process the unbond batches
+    pub unbond: bool,
+    /// Claim unbonded tokens and rewards for any NFT
+    pub claim: bool,
+    /// Compound the rewards
+    pub compound: bool,
+}
+
+impl AgentPermissions {
+    pub fn all() -> Self {
+        AgentPermissions { bond: true, unbond: true, claim: true, compound: true }
+    }
+
+    pub fn allows(&self, action: AgentAction) -> bool {
+        match action {
+            AgentAction::Bond => self.bond,
+            AgentAction::Unbond => self.unbond,
+            AgentAction::Claim => self.claim,
+            AgentAction::Compound => self.compound,
+        }
+    }
+}
+
+pub enum AgentAction {
+    Bond,
+    Unbond,
+    Claim,
+    Compound,
+}
+
+// Addresses proposed by the manager for a role, each one takes the role once it accepts. pk: (role, address)
+// The manager and the treasury have one proposal at most, every agent of the set has its own
+pub const ROLE_PROPOSALS: Map<(&str, &str), RoleProposal> = Map::new("role_proposals");
+
+#[cw_serde]
+pub enum Role {
+    Manager,
+    Agent,
+    Treasury,
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Role::Manager => write!(f, "manager"),
+            Role::Agent => write!(f, "agent"),
+            Role::Treasury => write!(f, "treasury"),
+        }
+    }
+}
+
+#[cw_serde]
+pub struct RoleProposal {
+    pub address: String,
+    /// Permissions of a proposed agent
+    pub permissions: Option<AgentPermissions>,
+    /// The proposal can not be accepted after it expires
+    pub expires: Option<Expiration>,
+}
+
+// cw721 collection whose tokens are the nft_id
+pub const NFT_CONTRACT: Item<Addr> = Item::new("nft_contract");
+
+// When set, NFT owners can bond, unbond and claim for their own NFTs
+pub const SELF_SERVICE: Item<bool> = Item::new("self_service");
+
+// NFTs held by this contract, with the address that sent each one. pk: nft_id
+pub const ESCROWED_NFTS: Map<&str, Addr> = Map::new("escrowed_nfts");
+
+
+
+// cw721 token id of an NFT
+pub type TokenId = String;
+
+// Legacy claims, created per undelegation before unbond batches and keyed by the token id as an Addr.
+// migrate_legacy_claims moves them to UNBOND_CLAIMS
+pub const LEGACY_CLAIMS: Map<&str, Vec<Claim>> = Map::new("claims");
+
+// Tokens bonded on behalf of every NFT. pk: nft_id
+pub const POSITIONS: Map<&str, NftPosition> = Map::new("positions");
+
+// Shares of BONDED owned by all the NFTs. Compounded rewards grow BONDED without minting shares
+pub const TOTAL_SHARES: Item<Uint128> = Item::new("total_shares");
+
+// What happens to the rewards withdrawn from the validators
+pub const REWARD_MODE: Item<RewardMode> = Item::new("reward_mode");
 
+#[cw_serde]
+pub enum RewardMode {
+    /// Rewards are kept in the contract for the NFTs to claim them
+    Payout,
+    /// Rewards are delegated again, growing the bonded amount of every NFT
+    Compound,
+}
+
+impl fmt::Display for RewardMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RewardMode::Payout => write!(f, "payout"),
+            RewardMode::Compound => write!(f, "compound"),
+        }
+    }
+}
 
-// Claims(Map<&Addr, Vec<Claim>>)      struct Claim {amount: Uint128,release_at: Expiration,}
-pub const CLAIMS: Claims = Claims::new("claims");
+// Validators charging a higher commission do not receive new bonds
+pub const MAX_COMMISSION: Item<Decimal> = Item::new("max_commission");
+
+// How an unbond is split between the validators
+pub const UNBOND_STRATEGY: Item<UnbondStrategy> = Item::new("unbond_strategy");
+
+#[cw_serde]
+pub enum UnbondStrategy {
+    /// Each validator unbonds in proportion to its bonded
+    Proportional,
+    /// Validators are emptied one after the other, largest bonded first
+    LargestFirst,
+    /// Every validator unbonds the same, a validator without enough bonded unbonds all it has
+    EvenSplit,
+}
+
+impl fmt::Display for UnbondStrategy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UnbondStrategy::Proportional => write!(f, "proportional"),
+            UnbondStrategy::LargestFirst => write!(f, "largest_first"),
+            UnbondStrategy::EvenSplit => write!(f, "even_split"),
+        }
+    }
+}
+
+// Release time of each undelegation from a validator, to keep within the unbonding entries limit of the chain
+pub const UNBONDING_ENTRIES: Map<&str, Vec<Expiration>> = Map::new("unbonding_entries");
+
+// Seconds an unbond batch collects requests before it can be undelegated
+pub const UNBOND_EPOCH: Item<u64> = Item::new("unbond_epoch");
+
+// Id of the batch collecting the unbond requests
+pub const PENDING_BATCH: Item<u64> = Item::new("pending_batch");
+
+// Unbond requests undelegated together. pk: batch_id
+pub const UNBOND_BATCHES: Map<u64, UnbondBatch> = Map::new("unbond_batches");
+
+#[cw_serde]
+pub struct UnbondBatch {
+    /// Its shares are already burnt, it is not part of BONDED any more
+    pub total: Uint128,
+    pub started_at: Timestamp,
+    /// Set once the batch is undelegated
+    pub release_at: Option<Expiration>,
+}
+
+impl UnbondBatch {
+    pub fn new(started_at: Timestamp) -> Self {
+        UnbondBatch { total: Uint128::zero(), started_at, release_at: None }
+    }
+}
+
+// Tokens unbonded by an NFT in a batch, claimable once the batch is released. pk: (nft_id, batch_id)
+pub const UNBOND_CLAIMS: Map<(&str, u64), Uint128> = Map::new("unbond_claims");
+
+// Rewards realised by WithdrawDelegatorReward, per validator and in total
+pub const VALIDATOR_REWARDS: Map<&str, Uint128> = Map::new("validator_rewards");
+pub const TOTAL_REWARDS: Item<Uint128> = Item::new("total_rewards");
+
+// Reward withdrawal in progress, only set while its submessages are executed
+pub const REWARD_WITHDRAWAL: Item<RewardWithdrawal> = Item::new("reward_withdrawal");
+
+#[cw_serde]
+pub struct RewardWithdrawal {
+    /// Validators whose withdrawal has not replied yet, in execution order
+    pub validators: Vec<String>,
+    /// Contract balance after the last withdrawal
+    pub balance: Uint128,
+    /// Rewards realised so far
+    pub rewards: Uint128,
+    /// What to do with the rewards once all of them are withdrawn
+    pub mode: RewardMode,
+}
+
+// Rewards earned by every share since instantiation
+pub const REWARD_INDEX: Item<Decimal> = Item::new("reward_index");
+// Rewards credited to NFTs and not claimed yet
+pub const PENDING_REWARDS: Item<Uint128> = Item::new("pending_rewards");
+// Share of the collected rewards sent to the treasury, in basis points
+pub const FEE_BPS: Item<u64> = Item::new("fee_bps");
+
+#[cw_serde]
+pub struct NftPosition {
+    /// Shares of BONDED owned by the NFT
+    pub shares: Uint128,
+    /// Time of the first bond of the NFT
+    pub bonded_since: Timestamp,
+    /// REWARD_INDEX when rewards were last credited to the NFT
+    pub reward_index: Decimal,
+    /// Rewards credited to the NFT and not claimed yet
+    pub rewards: Uint128,
+}
+
+impl NftPosition {
+    pub fn new(bonded_since: Timestamp, reward_index: Decimal) -> Self {
+        Self {
+            shares: Uint128::zero(),
+            bonded_since,
+            reward_index,
+            rewards: Uint128::zero(),
+        }
+    }
+
+    /// Rewards earned since the last checkpoint, not credited yet
+    pub fn unsettled_rewards(&self, reward_index: Decimal) -> Uint128 {
+        self.shares * (reward_index - self.reward_index)
+    }
+
+    /// Credits the rewards earned since the last checkpoint. Must be called before `shares` changes.
+    pub fn settle_rewards(&mut self, reward_index: Decimal) {
+        self.rewards += self.unsettled_rewards(reward_index);
+        self.reward_index = reward_index;
+    }
+}
 
 #[cw_serde]
 pub struct ValidatorInfo{
@@ -41,6 +273,32 @@ pub struct ValidatorInfo{
     pub unbonding_period: Duration,
     pub bonded: u128,
     pub claimed: u128,
+    /// target share of the bonded tokens, relative to the weights of the other validators
+    pub weight: u64,
+    /// bonds never take bonded past this amount
+    pub max_bonded: Option<u128>,
+}
+
+impl ValidatorInfo {
+    pub fn has_room_for(&self, amount: u128) -> bool {
+        match self.max_bonded {
+            Some(max_bonded) => self.bonded.checked_add(amount).is_some_and(|bonded| bonded <= max_bonded),
+            None => true,
+        }
+    }
+}
+
+// ValidatorInfo as stored by versions before weights and caps, read by the migration. pk: validator address
+pub const LEGACY_VALIDATORS: Map<&str, LegacyValidatorInfo> = Map::new("validator_info");
+
+#[cw_serde]
+pub struct LegacyValidatorInfo {
+    pub bond_denom: String,
+    pub unbonding_period: Duration,
+    pub bonded: u128,
+    pub claimed: u128,
+    pub weight: Option<u64>,
+    pub max_bonded: Option<u128>,
 }
 
 pub struct ValidatorIndexes<'a> {
@@ -62,6 +320,13 @@ pub struct State <'a>
     pub validator: IndexedMap<'a, &'a str, ValidatorInfo, ValidatorIndexes<'a>>,
 }
 
+impl<'a> Default for State<'a>
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl<'a> State<'a>
 {
     pub fn new() -> Self {
@@ -70,8 +335,8 @@ impl<'a> State<'a>
             validator: IndexedMap::new(
                 "validator_info",
             ValidatorIndexes { 
-                bonded: MultiIndex::new(|_pk,d| d.bonded.clone(),"validatorinfo","validatorinfo__bonded"),
-                claimed: MultiIndex::new(|_pk,d| d.claimed.clone(),"validatorinfo","validatorinfo__claimed"),
+                bonded: MultiIndex::new(|_pk,d| d.bonded,"validator_info","validatorinfo__bonded"),
+                claimed: MultiIndex::new(|_pk,d| d.claimed,"validator_info","validatorinfo__claimed"),
                 },
             )
         }